@@ -0,0 +1,142 @@
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// the file of the baseline in the run directory
+pub const BASELINE_FILE: &str = "baseline.json";
+
+/// the idle power of one enabled node at the start state
+#[derive(Clone, PartialEq, Debug)]
+pub struct NodeIdle {
+    pub name: String,
+    pub idle_w: f64,
+    pub stddev_w: f64,
+}
+
+impl NodeIdle {
+    pub fn to_value(&self) -> Value {
+        json!({"name": self.name, "idle_w": self.idle_w, "stddev_w": self.stddev_w})
+    }
+    pub fn from_value(v: &Value) -> Option<NodeIdle> {
+        Some(NodeIdle {
+            name: v["name"].as_str()?.to_string(),
+            idle_w: v["idle_w"].as_f64()?,
+            stddev_w: v["stddev_w"].as_f64().unwrap_or(0.0),
+        })
+    }
+}
+
+/// the power of the idle cluster at the start state, measured after the power is stable
+#[derive(Clone, PartialEq, Debug)]
+pub struct Baseline {
+    pub idle_w: f64,
+    pub stddev_w: f64,
+    pub readings: usize,
+    /// the idle power of each enabled node, read over the same window
+    pub nodes: Vec<NodeIdle>,
+}
+
+impl Baseline {
+    pub fn to_value(&self) -> Value {
+        json!({
+            "idle_w": self.idle_w,
+            "stddev_w": self.stddev_w,
+            "readings": self.readings,
+            "nodes": self.nodes.iter().map(|n| n.to_value()).collect::<Vec<Value>>(),
+        })
+    }
+    pub fn from_value(v: &Value) -> Option<Baseline> {
+        Some(Baseline {
+            idle_w: v["idle_w"].as_f64()?,
+            stddev_w: v["stddev_w"].as_f64().unwrap_or(0.0),
+            readings: v["readings"].as_u64().unwrap_or(0) as usize,
+            nodes: v["nodes"].as_array()
+                .map(|a| a.iter().filter_map(NodeIdle::from_value).collect())
+                .unwrap_or_default(),
+        })
+    }
+    pub fn node(&self, name: &str) -> Option<&NodeIdle> {
+        self.nodes.iter().find(|n| n.name == name)
+    }
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        fs::write(dir.join(BASELINE_FILE), serde_json::to_string_pretty(&self.to_value()).unwrap())
+    }
+    /// the baseline of an earlier run in `dir`, if any
+    pub fn load(dir: &Path) -> Option<Baseline> {
+        let text = fs::read_to_string(dir.join(BASELINE_FILE)).ok()?;
+        Baseline::from_value(&serde_json::from_str(&text).ok()?)
+    }
+    /// the power above the idle cluster, never below 0
+    pub fn net(&self, power: usize) -> usize {
+        (power as f64 - self.idle_w).max(0.0).round() as usize
+    }
+}
+
+/// the mean power of the lines of a power log, `42.5% 1300 400 node1=700 node2=600`,
+/// and the mean above `baseline`, for the cluster and for each node
+pub fn power_summary(log: &str, baseline: Option<&Baseline>) -> Value {
+    let powers: Vec<usize> = log.lines()
+        .filter_map(|l| l.split_whitespace().nth(1))
+        .filter_map(|x| x.parse().ok())
+        .collect();
+    if powers.is_empty() {
+        return Value::Null;
+    }
+    let mean = powers.iter().sum::<usize>() as f64 / powers.len() as f64;
+    // the readings of each node, in the order the nodes first appear
+    let mut nodes: Vec<(&str, Vec<usize>)> = vec![];
+    for (name, x) in log.lines().flat_map(|l| l.split_whitespace()).filter_map(|x| x.split_once('=')) {
+        let x = match x.parse() {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        match nodes.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, a)) => a.push(x),
+            None => nodes.push((name, vec![x])),
+        }
+    }
+    let nodes: Map<String, Value> = nodes.into_iter().map(|(name, a)| {
+        let node_mean = a.iter().sum::<usize>() as f64 / a.len() as f64;
+        let idle = baseline.and_then(|b| b.node(name));
+        (name.to_string(), json!({
+            "readings": a.len(),
+            "mean_w": node_mean,
+            "mean_net_w": idle.map(|n| (node_mean - n.idle_w).max(0.0)),
+        }))
+    }).collect();
+    json!({
+        "readings": powers.len(),
+        "mean_w": mean,
+        "mean_net_w": baseline.map(|b| (mean - b.idle_w).max(0.0)),
+        "nodes": nodes,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_baseline() {
+        let nodes = vec![
+            NodeIdle { name: "node1".to_string(), idle_w: 500.0, stddev_w: 2.5 },
+            NodeIdle { name: "node2".to_string(), idle_w: 400.0, stddev_w: 2.0 },
+        ];
+        let b = Baseline { idle_w: 900.0, stddev_w: 4.5, readings: 10, nodes };
+        assert_eq!(Baseline::from_value(&b.to_value()), Some(b.clone()));
+        assert_eq!(b.net(1350), 450);
+        assert_eq!(b.net(850), 0);
+        let summary = power_summary("10.00% 1300 400 node1=700 node2=600\n20.00% 1500 600 node1=800 node2=700\n", Some(&b));
+        assert_eq!(summary, json!({
+            "readings": 2,
+            "mean_w": 1400.0,
+            "mean_net_w": 500.0,
+            "nodes": {
+                "node1": {"readings": 2, "mean_w": 750.0, "mean_net_w": 250.0},
+                "node2": {"readings": 2, "mean_w": 650.0, "mean_net_w": 250.0},
+            },
+        }));
+        let summary = power_summary("10.00% 1300\n", None);
+        assert_eq!(summary, json!({"readings": 1, "mean_w": 1300.0, "mean_net_w": null, "nodes": {}}));
+    }
+}
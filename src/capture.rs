@@ -0,0 +1,113 @@
+use crate::State;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// where a line of output comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+    /// stdout and stderr merged by a pseudo-terminal
+    Terminal,
+    /// messages of the side channel
+    Channel,
+}
+
+impl Stream {
+    fn tag(&self) -> &'static str {
+        match self {
+            Stream::Stdout => "out",
+            Stream::Stderr => "err",
+            Stream::Terminal => "pty",
+            Stream::Channel => "chn",
+        }
+    }
+    fn from_tag(s: &str) -> Option<Stream> {
+        match s {
+            "out" => Some(Stream::Stdout),
+            "err" => Some(Stream::Stderr),
+            "pty" => Some(Stream::Terminal),
+            "chn" => Some(Stream::Channel),
+            _ => None,
+        }
+    }
+    /// only lines of these streams are matched against hints
+    pub fn carries_hints(&self) -> bool {
+        matches!(self, Stream::Stdout | Stream::Terminal)
+    }
+}
+
+impl Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.tag())
+    }
+}
+
+/// a line read back from a capture file
+#[derive(Debug, PartialEq)]
+pub struct Record<'a> {
+    pub at: Duration,
+    pub stream: Stream,
+    pub progress: f64,
+    pub state: &'a str,
+    pub text: &'a str,
+}
+
+/// the output of the application, one tab separated record per line:
+/// `seconds  stream  progress  state  text`
+/// seconds are counted from the start of the launcher with a monotonic clock
+pub struct Capture {
+    file: LineWriter<File>,
+}
+
+impl Capture {
+    pub fn create<P: AsRef<Path>>(path: P) -> Capture {
+        Capture {
+            file: LineWriter::new(File::create(path).expect("can not create the capture file")),
+        }
+    }
+    pub fn write(&mut self, at: Duration, stream: Stream, progress: f64, state: &State, text: &str) {
+        let text = text.trim_end_matches(['\n', '\r']);
+        if let Err(e) = writeln!(self.file, "{:.6}\t{}\t{:.2}\t{}\t{}",
+            at.as_secs_f64(), stream, progress, state, text) {
+            println!("{}", e);
+        }
+    }
+}
+
+/// parse a line written by `Capture::write`
+pub fn parse_record(s: &str) -> Option<Record<'_>> {
+    let mut fields = s.splitn(5, '\t');
+    let at = fields.next()?.parse::<f64>().ok()?;
+    let stream = Stream::from_tag(fields.next()?)?;
+    let progress = fields.next()?.parse::<f64>().ok()?;
+    let state = fields.next()?;
+    let text = fields.next()?;
+    Some(Record {
+        at: Duration::from_secs_f64(at),
+        stream,
+        progress,
+        state,
+        text,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_parse_record() {
+        let r = parse_record("12.500000\tout\t42.50\tState{GPU_Freq: 765MHz,}\tPCOL\tNBMIN").unwrap();
+        assert_eq!(r, Record {
+            at: Duration::from_millis(12500),
+            stream: Stream::Stdout,
+            progress: 42.5,
+            state: "State{GPU_Freq: 765MHz,}",
+            text: "PCOL\tNBMIN",
+        });
+        assert_eq!(parse_record("[ 1.0] PCOL"), None);
+    }
+}
@@ -0,0 +1,122 @@
+use std::env;
+use std::fmt::{self, Display};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use nix::sys::stat::Mode;
+use nix::unistd::mkfifo;
+use crate::tempdir::TempDir;
+
+/// a side channel for instrumented applications:
+/// the launcher creates a FIFO and exports its path in this variable,
+/// the application writes one message per line, `PHASE name`, `PROGRESS 42.5` or `HINT text`
+pub const CHANNEL_ENV: &str = "APP_LAUNCHER_CHANNEL";
+
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Phase(String),
+    Progress(f64),
+    Hint(String),
+}
+
+impl Message {
+    pub fn parse(s: &str) -> Option<Message> {
+        let s = s.trim();
+        let (kind, rest) = match s.split_once(char::is_whitespace) {
+            Some((k, r)) => (k, r.trim()),
+            None => (s, ""),
+        };
+        match kind {
+            "PHASE" if !rest.is_empty() => Some(Message::Phase(rest.to_string())),
+            "PROGRESS" => rest.trim_end_matches('%').parse().ok().map(Message::Progress),
+            "HINT" => Some(Message::Hint(rest.to_string())),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Message::Phase(x) => write!(f, "PHASE {}", x),
+            Message::Progress(x) => write!(f, "PROGRESS {}", x),
+            Message::Hint(x) => write!(f, "HINT {}", x),
+        }
+    }
+}
+
+/// the FIFO owned by the launcher, removed with its directory when dropped
+pub struct Channel {
+    /// a directory only the launcher may enter, so no one else can take the path first
+    _dir: TempDir,
+    path: PathBuf,
+}
+
+impl Channel {
+    pub fn create() -> io::Result<Channel> {
+        let dir = TempDir::create("app_launcher.channel")?;
+        let path = dir.path().join("channel.fifo");
+        mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR)?;
+        Ok(Channel { _dir: dir, path })
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// open the reading side
+    /// it is opened for writing too, so reading never sees the end when a writer closes it
+    pub fn open(&self) -> io::Result<File> {
+        OpenOptions::new().read(true).write(true).open(&self.path)
+    }
+}
+
+
+/// the helper for applications and wrapper scripts
+/// without a launcher (no `APP_LAUNCHER_CHANNEL`) every message is dropped
+pub struct Reporter {
+    file: Option<File>,
+}
+
+impl Reporter {
+    pub fn from_env() -> Reporter {
+        let file = env::var_os(CHANNEL_ENV)
+            .and_then(|p| OpenOptions::new().write(true).open(p).ok());
+        Reporter { file }
+    }
+    pub fn send(&mut self, m: &Message) -> io::Result<()> {
+        match &mut self.file {
+            // one write per message, lines shorter than PIPE_BUF are never interleaved
+            Some(f) => f.write_all(format!("{}\n", m).as_bytes()),
+            None => Ok(()),
+        }
+    }
+    pub fn phase(&mut self, name: &str) -> io::Result<()> {
+        self.send(&Message::Phase(name.to_string()))
+    }
+    pub fn progress(&mut self, progress: f64) -> io::Result<()> {
+        self.send(&Message::Progress(progress))
+    }
+    pub fn hint(&mut self, text: &str) -> io::Result<()> {
+        self.send(&Message::Hint(text.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_parse_message() {
+        assert_eq!(Message::parse("PHASE warmup\n"), Some(Message::Phase("warmup".to_string())));
+        assert_eq!(Message::parse("PROGRESS 42.5%"), Some(Message::Progress(42.5)));
+        assert_eq!(Message::parse("HINT PCOL  NBMIN"), Some(Message::Hint("PCOL  NBMIN".to_string())));
+        assert_eq!(Message::parse("PROGRESS lots"), None);
+        assert_eq!(Message::parse("hello"), None);
+    }
+    #[test]
+    fn test_channel_removed() {
+        let channel = Channel::create().unwrap();
+        let path = channel.path().to_path_buf();
+        assert!(path.exists());
+        drop(channel);
+        assert!(!path.exists() && !path.parent().unwrap().exists());
+    }
+}
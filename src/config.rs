@@ -0,0 +1,353 @@
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde_json::{Map, Value};
+use crate::tempdir::TempDir;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// how deep presets can be based on other presets
+const MAX_PRESET_DEPTH: usize = 8;
+
+/// the parts of application files merged key by key, the other keys are replaced
+const MERGED_BY_KEY: [&str; 4] = ["start_state", "variables", "presets", "frequency_table"];
+
+/// read a JSON, TOML (`.toml`) or YAML (`.yaml`, `.yml`) file, chosen by the extension
+pub fn read(path: &Path) -> Result<Value, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let value = match path.extension().and_then(|x| x.to_str()) {
+        Some("toml") => toml::from_str(&text).map_err(|e| format!("{}", e)),
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&text).map_err(|e| format!("{}", e)),
+        _ => serde_json::from_str(&text).map_err(|e| format!("{}", e)),
+    };
+    value.map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// the cluster file as JSON, a converted copy lives in a private temporary directory removed when this is dropped
+pub struct ClusterJson {
+    path: PathBuf,
+    _dir: Option<TempDir>,
+}
+
+impl ClusterJson {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// the power controller only reads JSON, a cluster file in another format is written as JSON in the temporary directory
+pub fn cluster_json(path: &Path) -> Result<ClusterJson, String> {
+    match path.extension().and_then(|x| x.to_str()) {
+        Some("toml") | Some("yaml") | Some("yml") => {
+            let text = serde_json::to_string_pretty(&read(path)?).unwrap();
+            let dir = TempDir::create("app_launcher.cluster").map_err(|e| format!("the temporary directory: {}", e))?;
+            let json = dir.path().join("cluster.json");
+            OpenOptions::new().write(true).create_new(true).open(&json)
+                .and_then(|mut f| f.write_all(text.as_bytes()))
+                .map_err(|e| format!("{}: {}", json.display(), e))?;
+            Ok(ClusterJson { path: json, _dir: Some(dir) })
+        },
+        _ => Ok(ClusterJson { path: path.to_path_buf(), _dir: None }),
+    }
+}
+
+/// call `f` with the cluster file as JSON, the temporary copy of a TOML or YAML file is removed afterwards, even if `f` panics
+pub fn with_cluster_json<T, F: FnOnce(&Path) -> T>(path: &Path, f: F) -> Result<T, String> {
+    let json = cluster_json(path)?;
+    Ok(f(json.path()))
+}
+
+/// put `top` over `base`: actions of `"strategy"` are appended,
+/// the fields of the start state, variables, presets and frequency tables are merged, `top` winning
+fn merge(base: &mut Map<String, Value>, top: Map<String, Value>) {
+    for (k, x) in top {
+        match (base.get_mut(&k), x) {
+            (Some(Value::Array(a)), Value::Array(b)) if k == "strategy" => a.extend(b),
+            (Some(Value::Object(a)), Value::Object(b)) if MERGED_BY_KEY.contains(&k.as_str()) => a.extend(b),
+            (_, x) => {
+                base.insert(k, x);
+            },
+        }
+    }
+}
+
+fn compose(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Map<String, Value>, String> {
+    let canonical = fs::canonicalize(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if stack.contains(&canonical) {
+        return Err(format!("{} includes itself", path.display()));
+    }
+    let mut app = match read(path)? {
+        Value::Object(x) => x,
+        _ => return Err(format!("{}: the application file must be an object", path.display())),
+    };
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut parents = vec![];
+    match app.remove("extends") {
+        Some(Value::String(x)) => parents.push(dir.join(x)),
+        Some(_) => return Err(format!("{}: extends must be a file name", path.display())),
+        None => {},
+    }
+    match app.remove("include") {
+        Some(Value::Array(a)) => for x in a {
+            parents.push(dir.join(x.as_str().ok_or(format!("{}: include must be a list of file names", path.display()))?));
+        },
+        Some(_) => return Err(format!("{}: include must be a list of file names", path.display())),
+        None => {},
+    }
+    stack.push(canonical);
+    let mut merged = Map::new();
+    for p in parents {
+        merge(&mut merged, compose(&p, stack)?);
+    }
+    stack.pop();
+    merge(&mut merged, app);
+    Ok(merged)
+}
+
+/// read an application file with the files it is based on:
+/// first the one of `"extends"`, then the ones of `"include"` in order, then the file itself,
+/// names are relative to the file
+pub fn load(path: &Path) -> Result<Value, String> {
+    compose(path, &mut vec![]).map(Value::Object)
+}
+
+/// `--set name=value`, the value is read as JSON when it can be, as a string otherwise
+pub fn parse_override(s: &str) -> Result<(String, Value), String> {
+    let (name, value) = s.split_once('=').ok_or(format!("need name=value instead of {}", s))?;
+    let value = serde_json::from_str(value.trim()).unwrap_or(Value::String(value.trim().to_string()));
+    Ok((name.trim().to_string(), value))
+}
+
+/// replace `${name}` by the value of the variable,
+/// a string which is only `${name}` becomes the value itself, so numbers stay numbers
+/// a name which is not a variable is left as it is, so that shell commands keep `${HOME}`
+fn substitute(v: Value, variables: &Map<String, Value>) -> Result<Value, String> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"\$\{(\w+)\}").unwrap();
+        static ref WHOLE: Regex = Regex::new(r"^\$\{(\w+)\}$").unwrap();
+    }
+    match v {
+        Value::String(s) => {
+            if let Some(x) = WHOLE.captures(&s).and_then(|caps| variables.get(&caps[1])) {
+                return Ok(x.clone());
+            }
+            Ok(Value::String(RE.replace_all(&s, |caps: &Captures| match variables.get(&caps[1]) {
+                Some(Value::String(x)) => x.clone(),
+                Some(x) => x.to_string(),
+                None => caps[0].to_string(),
+            }).into_owned()))
+        },
+        Value::Array(a) => a.into_iter().map(|x| substitute(x, variables)).collect::<Result<_, _>>().map(Value::Array),
+        Value::Object(o) => o.into_iter()
+            .map(|(k, x)| substitute(x, variables).map(|x| (k, x)))
+            .collect::<Result<_, _>>()
+            .map(Value::Object),
+        x => Ok(x),
+    }
+}
+
+/// a state written as `{"Preset": "boost", "Time": 500}` is the preset with the other fields on top of it
+fn expand_preset(v: &Value, presets: &Map<String, Value>, depth: usize) -> Result<Value, String> {
+    let obj = match v.as_object() {
+        Some(x) => x,
+        None => return Ok(v.clone()),
+    };
+    let name = match obj.get("Preset") {
+        Some(x) => x.as_str().ok_or("Preset must be a string")?,
+        None => return Ok(v.clone()),
+    };
+    if depth >= MAX_PRESET_DEPTH {
+        return Err(format!("preset {} is based on itself", name));
+    }
+    let preset = presets.get(name).ok_or(format!("unknown preset {}", name))?;
+    let mut state = match expand_preset(preset, presets, depth + 1)? {
+        Value::Object(x) => x,
+        _ => return Err(format!("preset {} must be an object", name)),
+    };
+    for (k, x) in obj {
+        if k != "Preset" {
+            state.insert(k.clone(), x.clone());
+        }
+    }
+    Ok(Value::Object(state))
+}
+
+/// turn a template into a plain application file:
+/// `"variables"` are set (`overrides` first) and substituted, `"presets"` are expanded in the start state and in actions
+pub fn resolve(app: Value, overrides: &[(String, Value)]) -> Result<Value, String> {
+    let mut app = match app {
+        Value::Object(x) => x,
+        _ => return Err("the application file must be an object".to_string()),
+    };
+    let mut variables = match app.remove("variables") {
+        Some(Value::Object(x)) => x,
+        Some(_) => return Err("variables must be an object".to_string()),
+        None => Map::new(),
+    };
+    for (name, value) in overrides {
+        if !variables.contains_key(name) {
+            return Err(format!("unknown variable {}, it must be declared in \"variables\"", name));
+        }
+        variables.insert(name.clone(), value.clone());
+    }
+    let mut app = match substitute(Value::Object(app), &variables)? {
+        Value::Object(x) => x,
+        _ => unreachable!(),
+    };
+    let presets = match app.remove("presets") {
+        Some(Value::Object(x)) => x,
+        Some(_) => return Err("presets must be an object".to_string()),
+        None => Map::new(),
+    };
+    if let Some(s) = app.get_mut("start_state") {
+        *s = expand_preset(s, &presets, 0)?;
+    }
+    if let Some(Value::Array(steps)) = app.get_mut("preparation") {
+        for s in steps.iter_mut().filter_map(|s| s.as_object_mut()) {
+            for key in ["State", "Hold"] {
+                if let Some(x) = s.get_mut(key) {
+                    *x = expand_preset(x, &presets, 0)?;
+                }
+            }
+        }
+    }
+    if let Some(x) = app.get_mut("retry").and_then(|r| r.get_mut("safe_state")) {
+        *x = expand_preset(x, &presets, 0)?;
+    }
+    if let Some(Value::Array(actions)) = app.get_mut("strategy") {
+        for a in actions {
+            if let Some(Value::Array(steps)) = a.get_mut("action") {
+                for s in steps {
+                    *s = expand_preset(s, &presets, 0)?;
+                }
+            }
+        }
+    }
+    Ok(Value::Object(app))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn template() -> Value {
+        serde_json::from_str(r#"
+        {
+            "variables": {"gpu_peak": 825, "hint": "PCOL"},
+            "presets": {
+                "idle": {"GPU_Freq": 390, "CPU_Freq": 900, "Fan_Speed": 40},
+                "boost": {"Preset": "idle", "GPU_Freq": "${gpu_peak}"}
+            },
+            "start_state": {"Preset": "idle", "Time": 0},
+            "strategy": [
+                {"hint": "${hint}", "action": [{"Preset": "boost", "CPU_Freq": "${gpu_peak} + 200"}]}
+            ]
+        }
+        "#).unwrap()
+    }
+    #[test]
+    fn test_presets_and_variables() {
+        let app = resolve(template(), &[]).unwrap();
+        assert!(app.get("variables").is_none() && app.get("presets").is_none());
+        assert_eq!(app["start_state"], serde_json::json!({"GPU_Freq": 390, "CPU_Freq": 900, "Fan_Speed": 40, "Time": 0}));
+        assert_eq!(app["strategy"][0]["hint"], "PCOL");
+        assert_eq!(app["strategy"][0]["action"][0], serde_json::json!({"GPU_Freq": 825, "CPU_Freq": "825 + 200", "Fan_Speed": 40}));
+    }
+    #[test]
+    fn test_unknown_variables_and_preparation() {
+        let mut t = template();
+        t["strategy"][0]["action"].as_array_mut().unwrap().push(serde_json::json!({"Shell": "echo ${HOME} ${hint} > ${OUT}/x"}));
+        t["preparation"] = serde_json::json!([{"Warm": "cd ${HOME}", "State": {"Preset": "boost"}}, {"Hold": {"Preset": "idle", "Time": 0}}]);
+        let app = resolve(t, &[]).unwrap();
+        assert_eq!(app["strategy"][0]["action"][1]["Shell"], "echo ${HOME} PCOL > ${OUT}/x");
+        assert_eq!(app["preparation"][0]["Warm"], "cd ${HOME}");
+        assert_eq!(app["preparation"][0]["State"], serde_json::json!({"GPU_Freq": 825, "CPU_Freq": 900, "Fan_Speed": 40}));
+        assert_eq!(app["preparation"][1]["Hold"]["GPU_Freq"], 390);
+    }
+    #[test]
+    fn test_override() {
+        let set = parse_override("gpu_peak=765").unwrap();
+        assert_eq!(set, ("gpu_peak".to_string(), Value::from(765)));
+        let app = resolve(template(), &[set]).unwrap();
+        assert_eq!(app["strategy"][0]["action"][0]["GPU_Freq"], 765);
+        assert!(resolve(template(), &[parse_override("gpu_max=765").unwrap()]).is_err());
+        let mut t = template();
+        t["start_state"]["Preset"] = Value::from("turbo");
+        assert!(resolve(t, &[]).is_err());
+    }
+    #[test]
+    fn test_extends_and_include() {
+        let dir = std::env::temp_dir().join(format!("app_launcher.config.{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("base.json"), r#"{
+            "application_path": "/bin/hpl",
+            "start_state": {"GPU_Freq": 390, "Fan_Speed": 40},
+            "strategy": [{"phase": "warmup", "action": [{"Fan_Speed": 80}]}]
+        }"#).unwrap();
+        fs::write(dir.join("fans.json"), r#"{"strategy": [{"hint": "T/V", "action": [{"Fan_Speed": 40}]}]}"#).unwrap();
+        fs::write(dir.join("hpl.json"), r#"{
+            "extends": "base.json",
+            "include": ["fans.json"],
+            "start_state": {"GPU_Freq": 600},
+            "strategy": [{"hint": "PCOL", "action": [{"GPU_Freq": 825}]}]
+        }"#).unwrap();
+        fs::write(dir.join("loop.json"), r#"{"extends": "loop.json"}"#).unwrap();
+        let app = load(&dir.join("hpl.json"));
+        let looped = load(&dir.join("loop.json"));
+        fs::remove_dir_all(&dir).unwrap();
+        let app = app.unwrap();
+        assert_eq!(app["application_path"], "/bin/hpl");
+        assert_eq!(app["start_state"], serde_json::json!({"GPU_Freq": 600, "Fan_Speed": 40}));
+        let triggers: Vec<&Value> = app["strategy"].as_array().unwrap().iter()
+            .map(|a| a.get("hint").unwrap_or(&a["phase"]))
+            .collect();
+        assert_eq!(triggers, vec!["warmup", "T/V", "PCOL"]);
+        assert!(looped.is_err());
+    }
+    #[test]
+    fn test_formats() {
+        let dir = std::env::temp_dir().join(format!("app_launcher.formats.{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("hpl.toml"), r#"
+            # comments are allowed
+            application_path = "/bin/hpl"
+            start_state = { GPU_Freq = 390, Time = 0 }
+            [[strategy]]
+            hint = "PCOL"
+            action = [{ GPU_Freq = "+75" }]
+        "#).unwrap();
+        fs::write(dir.join("hpl.yaml"), "
+# comments are allowed
+application_path: /bin/hpl
+start_state: {GPU_Freq: 390, Time: 0}
+strategy:
+  - hint: PCOL
+    action:
+      - GPU_Freq: '+75'
+").unwrap();
+        fs::write(dir.join("hpl.json"), r#"{
+            "application_path": "/bin/hpl",
+            "start_state": {"GPU_Freq": 390, "Time": 0},
+            "strategy": [{"hint": "PCOL", "action": [{"GPU_Freq": "+75"}]}]
+        }"#).unwrap();
+        let apps: Vec<Value> = ["hpl.toml", "hpl.yaml", "hpl.json"].iter().map(|x| load(&dir.join(x)).unwrap()).collect();
+        let cluster = cluster_json(&dir.join("hpl.yaml")).unwrap();
+        let converted = read(cluster.path()).unwrap();
+        let copy = cluster.path().to_path_buf();
+        drop(cluster);
+        assert!(!copy.exists());
+        let copy = with_cluster_json(&dir.join("hpl.yaml"), |p| { assert_eq!(read(p).unwrap(), converted); p.to_path_buf() }).unwrap();
+        assert!(!copy.exists());
+        let seen = std::sync::Mutex::new(None);
+        let panicked = std::panic::catch_unwind(|| with_cluster_json(&dir.join("hpl.yaml"), |p| {
+            *seen.lock().unwrap() = Some(p.to_path_buf());
+            panic!("the power controller can not read {}", p.display());
+        }));
+        assert!(panicked.is_err());
+        assert!(!seen.lock().unwrap().take().unwrap().exists());
+        assert_eq!(with_cluster_json(&dir.join("hpl.json"), |p| p.to_path_buf()).unwrap(), dir.join("hpl.json"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(apps[0], apps[2]);
+        assert_eq!(apps[1], apps[2]);
+        assert_eq!(converted, apps[2]);
+    }
+}
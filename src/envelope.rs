@@ -0,0 +1,139 @@
+use crate::state::Knob;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+/// the span the switches per second are counted in
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// the limits the values sent to the hardware stay in, written as
+/// `"envelope": {"min_fan_speed": 30, "ranges": {"GPU_Freq": [390, 1410]}, "max_switches_per_second": 4, "min_dwell_ms": 250}`
+/// a value out of its range is clamped, a switch of a component coming too soon is deferred until it may change
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Envelope {
+    ranges: HashMap<Knob, (usize, usize)>,
+    /// how many times a component, the CPUs, the GPUs or the fans, may change in a second
+    pub max_switches_per_second: Option<usize>,
+    /// the time a component keeps a value before it changes again
+    pub min_dwell: Duration,
+    /// the last changes of each component
+    switches: HashMap<&'static str, VecDeque<Duration>>,
+    pub clamped: usize,
+    /// switches held back until their component may change
+    pub deferred: usize,
+    /// deferred switches replaced by a later one before they are sent
+    pub dropped: usize,
+}
+
+impl From<&Value> for Envelope {
+    fn from(v: &Value) -> Self {
+        let obj = v.as_object().expect("envelope must be an object");
+        let mut ranges = HashMap::new();
+        if let Some(r) = obj.get("ranges") {
+            for (key, range) in r.as_object().expect("the ranges of envelope must be an object") {
+                let knob = Knob::from_key(key).unwrap_or_else(|| panic!("unknown knob {} in envelope", key));
+                let bounds: Vec<usize> = range.as_array()
+                    .filter(|a| a.len() == 2)
+                    .unwrap_or_else(|| panic!("the range of {} must be [min, max]", key))
+                    .iter()
+                    .map(|x| x.as_u64().expect("need a number") as usize)
+                    .collect();
+                assert!(bounds[0] <= bounds[1], "the range of {} is empty, {} is above {}", key, bounds[0], bounds[1]);
+                ranges.insert(knob, (bounds[0], bounds[1]));
+            }
+        }
+        if let Some(x) = obj.get("min_fan_speed") {
+            let min = x.as_u64().expect("min_fan_speed must be a number") as usize;
+            let max = ranges.get(&Knob::FanSpeed).map_or(100, |r| r.1);
+            assert!(min <= max, "min_fan_speed {} is above the highest fan speed {}", min, max);
+            ranges.insert(Knob::FanSpeed, (min, max));
+        }
+        Envelope {
+            ranges,
+            max_switches_per_second: obj.get("max_switches_per_second")
+                .map(|x| x.as_u64().filter(|x| *x > 0).expect("max_switches_per_second must be a positive number") as usize),
+            min_dwell: obj.get("min_dwell_ms")
+                .map_or(Duration::ZERO, |x| Duration::from_millis(x.as_u64().expect("need a number of milisecond"))),
+            ..Envelope::default()
+        }
+    }
+}
+
+impl Envelope {
+    /// the lowest and highest value allowed for `knob`, if any
+    pub fn range(&self, knob: Knob) -> Option<(usize, usize)> {
+        self.ranges.get(&knob).copied()
+    }
+    /// `value` moved into the range of `knob`
+    pub fn clamp(&self, knob: Knob, value: usize) -> usize {
+        match self.ranges.get(&knob) {
+            Some((min, max)) => value.max(*min).min(*max),
+            None => value,
+        }
+    }
+    /// how long `component` must wait from `now` before it may change, zero when it may at once
+    pub fn wait(&self, component: &'static str, now: Duration) -> Duration {
+        let last = match self.switches.get(component) {
+            Some(x) => x,
+            None => return Duration::ZERO,
+        };
+        let recent: Vec<Duration> = last.iter().copied().filter(|t| now.saturating_sub(*t) < RATE_WINDOW).collect();
+        let dwell = recent.last().map_or(Duration::ZERO, |t| (*t + self.min_dwell).saturating_sub(now));
+        let rate = match self.max_switches_per_second {
+            Some(max) if recent.len() >= max => (recent[recent.len() - max] + RATE_WINDOW).saturating_sub(now),
+            _ => Duration::ZERO,
+        };
+        dwell.max(rate)
+    }
+    /// whether `component` may change at `now`, the change is counted when it may
+    pub fn admit(&mut self, component: &'static str, now: Duration) -> Result<(), String> {
+        let last = self.switches.entry(component).or_default();
+        while last.front().is_some_and(|t| now.saturating_sub(*t) >= RATE_WINDOW) {
+            last.pop_front();
+        }
+        if let Some(t) = last.back() {
+            if now.saturating_sub(*t) < self.min_dwell {
+                return Err(format!("{} changed {:?} ago, less than the dwell of {:?}", component, now - *t, self.min_dwell));
+            }
+        }
+        if let Some(max) = self.max_switches_per_second {
+            if last.len() >= max {
+                return Err(format!("{} already changed {} times in the last second", component, last.len()));
+            }
+        }
+        last.push_back(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_envelope() {
+        let v = serde_json::from_str(r#"
+        {"min_fan_speed": 30, "ranges": {"GPU_Freq": [390, 1410]}, "max_switches_per_second": 2, "min_dwell_ms": 100}
+        "#).unwrap();
+        let mut e = Envelope::from(&v);
+        assert_eq!(e.clamp(Knob::FanSpeed, 0), 30);
+        assert_eq!(e.clamp(Knob::GpuFreq, 1500), 1410);
+        assert_eq!(e.clamp(Knob::CpuFreq, 3000), 3000);
+        let ms = Duration::from_millis;
+        assert!(e.admit("GPU", ms(0)).is_ok());
+        assert_eq!(e.wait("GPU", ms(50)), ms(50));
+        assert!(e.admit("GPU", ms(50)).is_err());
+        assert!(e.admit("FAN", ms(50)).is_ok());
+        assert!(e.admit("GPU", ms(200)).is_ok());
+        assert_eq!(e.wait("GPU", ms(400)), ms(600));
+        assert!(e.admit("GPU", ms(400)).is_err());
+        // the first change leaves the window
+        assert_eq!(e.wait("GPU", ms(1000)), Duration::ZERO);
+        assert!(e.admit("GPU", ms(1000)).is_ok());
+    }
+    #[test]
+    #[should_panic(expected = "is above")]
+    fn test_empty_range() {
+        let v = serde_json::from_str(r#"{"ranges": {"GPU_Freq": [1410, 390]}}"#).unwrap();
+        let _ = Envelope::from(&v);
+    }
+}
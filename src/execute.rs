@@ -1,103 +1,250 @@
-use crate::{State, StateManager};
+use crate::StateManager;
+use crate::step::Step;
+use crate::expr::Captures;
+use crate::capture::{Capture, Stream};
+use crate::channel::{Channel, Message, CHANNEL_ENV};
+use crate::lines::LineReader;
 use std::fmt::{self,Display};
-use std::io::{BufReader, BufRead};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::FromRawFd;
 use std::path::Path;
-use std::process::{ChildStdout, Command, Stdio};
-use log::info;
-use power_controller::Cluster;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{error, info, warn};
+use nix::pty::openpty;
+use nix::unistd::{close, dup};
 use regex::Regex;
 use serde_json::Value;
 use lazy_static::lazy_static;
 
 pub static mut PROGRESS:f64 = 0.0;
+/// how often a waiting step checks its condition when no output comes
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
+/// an action is triggered by a line of output matching `hint`,
+/// or by a `PHASE` message of the side channel equal to `phase`
 #[derive(Debug)]
 pub struct Action {
-    pub hint: Regex,
-    tune_set: Vec<State>
+    pub hint: Option<Regex>,
+    pub phase: Option<String>,
+    tune_set: Vec<Step>
+}
+
+/// where the executor is in an action which has not finished
+struct Running {
+    action: usize,
+    step: usize,
+    /// when the current step began
+    since: Duration,
+    /// the numbers caught by the named groups of the hint
+    captures: Captures,
+}
+
+enum Event {
+    Line(Stream, Instant, String),
+    /// an output of the application reaches its end
+    Closed,
 }
 pub struct Executor<'a> {
-    cluster: &'a Cluster,
     notice: Vec<Action>,
     notice_index: usize,
+    running: Option<Running>,
     state_manager: &'a mut StateManager<'a>,
     executable_file: String,
+    capture: Option<Capture>,
+    use_pty: bool,
 }   
 
 impl From<&Value> for Action {
     fn from(raw_data: &Value) -> Self{
         let obj = raw_data.as_object()
             .expect("can not convert the value to an object in action initialization");
+        let hint = obj.get("hint")
+            .map(|h| Regex::new(h.as_str().expect("hint must be a string")).unwrap());
+        let phase = obj.get("phase")
+            .map(|p| p.as_str().expect("phase must be a string").to_string());
+        assert!(hint.is_some() || phase.is_some(), "an action needs a hint or a phase");
         Action { 
-            hint: Regex::new(obj["hint"].as_str().unwrap()).unwrap(), 
+            hint, 
+            phase,
             tune_set: obj["action"]
                         .as_array()
-                        .expect("action must be an array of state")
+                        .expect("action must be an array of steps")
                         .iter()
-                        .map(|c| {State::from(c)})
+                        .map(|c| {Step::from(c)})
                         .collect::<_>()  
         }
     }
 }
 impl Action {
-    
-    pub fn act(&self, state_manager: &mut StateManager) {
-        info!("[action]{} is acted", &self);
+    /// make sure every raw command of the action can be parsed
+    pub fn check(&self, state_manager: &StateManager) -> Result<(), String> {
         for s in &self.tune_set {
-            state_manager.switch_state(s.clone())
+            s.check(state_manager).map_err(|e| format!("{:?} in ({}): {}", s, self.trigger(), e))?;
         }
+        Ok(())
     }
     pub fn find(&self, s: &str) -> bool {
-        match self.hint.find(s) {
-            Some(_x) => true,
+        match &self.hint {
+            Some(h) => h.is_match(s),
             None => false
         }
     }
+    /// the named groups of the hint in `s` which are numbers
+    pub fn captures(&self, s: &str) -> Captures {
+        let mut captures = Captures::new();
+        if let Some(h) = &self.hint {
+            if let Some(caps) = h.captures(s) {
+                for name in h.capture_names().flatten() {
+                    if let Some(x) = caps.name(name).and_then(|m| m.as_str().trim().parse().ok()) {
+                        captures.insert(name.to_string(), x);
+                    }
+                }
+            }
+        }
+        captures
+    }
+    pub fn on_phase(&self, name: &str) -> bool {
+        self.phase.as_deref() == Some(name)
+    }
+    /// what the action waits for, such as `hint: PCOL`
+    pub fn trigger(&self) -> String {
+        match (&self.hint, &self.phase) {
+            (Some(h), Some(p)) => format!("hint: {}, phase: {}", h, p),
+            (Some(h), None) => format!("hint: {}", h),
+            (None, Some(p)) => format!("phase: {}", p),
+            (None, None) => String::new()
+        }
+    }
 }
 
 impl Display for Action {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // Use `self.number` to refer to each positional data point.
-
-        write!(f, "(hint: {}, action_set: {:?})", self.hint,self.tune_set)
+        write!(f, "({}, action_set: {:?})", self.trigger(), self.tune_set)
     }
 }
 impl<'a> Executor<'a> {
-    pub fn new<P:'a + AsRef<Path>>(executable_file: P, raw_action_set: &'a Value, cluster: &'a Cluster, state_manager: &'a mut StateManager<'a>) 
+    pub fn new<P:'a + AsRef<Path>>(executable_file: P, raw_action_set: &'a Value, state_manager: &'a mut StateManager<'a>) 
     -> Executor<'a> {
-        let arr = raw_action_set.as_array().expect("need to input an action array");
-        let notice: Vec<Action> = arr.iter()
-                        .map(|c| {Action::from(c)})
-                        .collect::<_>();
-
+        let notice = Executor::parse_actions(raw_action_set, state_manager);
         Executor { 
-            cluster,
             notice, 
             notice_index: 0, 
+            running: None,
             state_manager, 
-            executable_file: executable_file.as_ref().to_str().unwrap().to_string()
+            executable_file: executable_file.as_ref().to_str().unwrap().to_string(),
+            capture: None,
+            use_pty: false
         }
         
     }
-    fn get_buffer(&self) -> Result<BufReader<ChildStdout>, std::io::Error>{
-        let mut child = match Command::new(&self.executable_file)
-                        .arg("2>&1")
-                        .stdout(Stdio::piped())
-                        .spawn()
-                        {
-                            Ok(c) => c,
-                            Err(e) => {
-                                info!("{}",e);
-                                return Err(e);
-                            }
-                        };
-        let stdout = child.stdout.take().unwrap();
-        Ok(BufReader::new(stdout))
-
+    /// an executor which runs nothing, lines are fed by `feed_line`
+    pub fn replay(raw_action_set: &'a Value, state_manager: &'a mut StateManager<'a>) -> Executor<'a> {
+        let notice = Executor::parse_actions(raw_action_set, state_manager);
+        Executor {
+            notice,
+            notice_index: 0,
+            running: None,
+            state_manager,
+            executable_file: String::new(),
+            capture: None,
+            use_pty: false
+        }
+    }
+    /// raw commands are checked here, so that a wrong one stops the launcher before the application starts
+    fn parse_actions(raw_action_set: &Value, state_manager: &StateManager) -> Vec<Action> {
+        let arr = raw_action_set.as_array().expect("need to input an action array");
+        let notice: Vec<Action> = arr.iter()
+            .map(|c| {Action::from(c)})
+            .collect::<_>();
+        for a in &notice {
+            if let Err(e) = a.check(state_manager) {
+                panic!("invalid command {}", e);
+            }
+        }
+        notice
+    }
+    /// write every line of output of the application to `capture`
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.capture = Some(capture);
+    }
+    /// run the application under a pseudo-terminal, so that its output is not buffered
+    pub fn set_pty(&mut self, use_pty: bool) {
+        self.use_pty = use_pty;
+    }
+    pub fn state_manager(&mut self) -> &mut StateManager<'a> {
+        self.state_manager
+    }
+    fn command(&self, channel: &Option<Channel>) -> Command {
+        let mut command = Command::new(&self.executable_file);
+        if let Some(c) = channel {
+            command.env(CHANNEL_ENV, c.path());
+        }
+        command
+    }
+    fn spawn(&self, channel: &Option<Channel>) -> Result<Child, std::io::Error>{
+        match self.command(channel)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(c) => Ok(c),
+            Err(e) => {
+                info!("{}",e);
+                Err(e)
+            }
+        }
+    }
+    /// spawn the application with stdout and stderr connected to a new pseudo-terminal,
+    /// the master side of the terminal is returned for reading
+    fn spawn_pty(&self, channel: &Option<Channel>) -> Result<(Child, File), io::Error> {
+        let pty = openpty(None, None)?;
+        let (stdout, stderr) = unsafe {
+            (File::from_raw_fd(dup(pty.slave)?), File::from_raw_fd(dup(pty.slave)?))
+        };
+        let child = self.command(channel)
+            .stdout(Stdio::from(stdout))
+            .stderr(Stdio::from(stderr))
+            .spawn();
+        // the child holds its own copies, the master sees EOF once they are closed
+        close(pty.slave)?;
+        match child {
+            Ok(c) => Ok((c, unsafe { File::from_raw_fd(pty.master) })),
+            Err(e) => {
+                info!("{}",e);
+                close(pty.master)?;
+                Err(e)
+            }
+        }
+    }
+    /// read lines from `output` in another thread, each line is sent with the instant it was read
+    fn forward<R: Read + Send + 'static>(output: R, stream: Stream, tx: Sender<Event>) {
+        thread::spawn(move || {
+            let mut reader = LineReader::new(output);
+            loop {
+                match reader.next_line() {
+                    Ok(None) => break,
+                    Ok(Some(s)) => {
+                        if tx.send(Event::Line(stream, Instant::now(), s)).is_err() {
+                            break;
+                        }
+                    },
+                    // linux reports EIO on the master of a pseudo-terminal once the application has exited
+                    Err(e) if e.raw_os_error() == Some(nix::libc::EIO) => break,
+                    Err(e) => {
+                        print!("{}",e);
+                        break;
+                    }
+                };
+            }
+            let _ = tx.send(Event::Closed);
+        });
     }
-    #[allow(unused)]
     fn get_power(&self) -> usize {
-        self.cluster.collect_power_data(0).total_power
+        self.state_manager.power()
     }
     fn check_process(s: &str) -> Option<f64>{
         lazy_static! {
@@ -114,54 +261,187 @@ impl<'a> Executor<'a> {
         };
         match caps.name("progress") {
             Some(x) => {
-                Some(x.as_str().parse().unwrap())
+                x.as_str().parse().ok()
             },
             None => None
         }
     }
+    fn set_progress(x: f64) {
+        println!("now the progress is {:.2}", x);
+        unsafe {
+            PROGRESS = x;
+            if x >= 100.0 {
+                crate::logger::STOP = true;
+            }
+        }
+    }
+    /// act on the current action, the next one is waited for afterwards
+    /// `kind` tells in the timeline what triggers it, a `"hint"` or a `"phase"`
+    fn fire(&mut self, kind: &'static str, captures: Captures) {
+        let trigger = self.notice[self.notice_index].trigger();
+        info!("[execution]{} is matched", trigger);
+        info!("[action]{} is acted", self.notice[self.notice_index]);
+        self.state_manager.timeline_mut().record(kind, trigger);
+        let since = self.state_manager.timeline().clock.now();
+        self.running = Some(Running { action: self.notice_index, step: 0, since, captures });
+        self.notice_index += 1;
+        self.advance(None);
+    }
+    /// go on with the running action until it ends or a wait step is not over yet
+    /// `line` is the line of output just read, if any
+    fn advance(&mut self, line: Option<&str>) {
+        loop {
+            let (action, step, since) = match &self.running {
+                Some(r) => (r.action, r.step, r.since),
+                None => return
+            };
+            let steps = &self.notice[action].tune_set;
+            if step >= steps.len() {
+                self.running = None;
+                return;
+            }
+            if let Step::Wait { condition, timeout } = &steps[step] {
+                let now = self.state_manager.timeline().clock.now();
+                let progress = unsafe { PROGRESS };
+                let power = if condition.needs_power() { self.get_power() } else { 0 };
+                if condition.holds(power, progress, line) {
+                    info!("[wait]{} after {:?}", condition, now.saturating_sub(since));
+                    self.state_manager.timeline_mut()
+                        .record("wait", format!("{} after {:?}", condition, now.saturating_sub(since)));
+                } else if now.saturating_sub(since) >= *timeout {
+                    warn!("[wait]{} does not happen in {:?}", condition, timeout);
+                    self.state_manager.timeline_mut()
+                        .record("wait", format!("{} timed out after {:?}", condition, timeout));
+                } else {
+                    return;
+                }
+            } else {
+                let captures = &self.running.as_ref().unwrap().captures;
+                steps[step].execute(self.state_manager, captures);
+            }
+            let now = self.state_manager.timeline().clock.now();
+            if let Some(r) = &mut self.running {
+                r.step += 1;
+                r.since = now;
+            }
+        }
+    }
+    /// check the wait step of the running action, when no output comes
+    pub fn tick(&mut self) {
+        if self.running.is_some() {
+            self.advance(None);
+        }
+    }
+    /// a line goes to the running action if there is one, otherwise it is matched against the next hint
+    fn match_hint(&mut self, s: &str) {
+        if self.running.is_some() {
+            self.advance(Some(s));
+        } else if self.notice_index < self.notice.len() && self.notice[self.notice_index].find(s) {
+            let captures = self.notice[self.notice_index].captures(s);
+            self.fire("hint", captures);
+        }
+    }
+    /// parse the progress of a line of output and act on the hint it matches
+    pub fn feed_line(&mut self, s: &str) {
+        if let Some(x) = Executor::check_process(s) {
+            Executor::set_progress(x);
+        }
+        self.match_hint(s);
+    }
+    /// act on a message of the side channel
+    pub fn feed_message(&mut self, m: Message) {
+        info!("[channel]{}", m);
+        match m {
+            Message::Phase(name) => {
+                if self.running.is_none() && self.notice_index < self.notice.len() && self.notice[self.notice_index].on_phase(&name) {
+                    self.fire("phase", Captures::new());
+                }
+            },
+            Message::Progress(x) => {
+                Executor::set_progress(x);
+                self.tick();
+            },
+            Message::Hint(s) => self.match_hint(&s)
+        }
+    }
     pub fn run(&mut self) {
-        info!("[execution]set buffer");
-        let mut buffer = self.get_buffer().unwrap();
+        info!("[execution]spawn the application");
+        let channel = match Channel::create() {
+            Ok(c) => Some(c),
+            Err(e) => {
+                warn!("[channel]can not create the side channel: {}", e);
+                None
+            }
+        };
+        let (tx, rx) = mpsc::channel();
+        let (mut child, mut open_outputs) = if self.use_pty {
+            let (child, master) = self.spawn_pty(&channel).unwrap();
+            Executor::forward(master, Stream::Terminal, tx.clone());
+            (child, 1)
+        } else {
+            let mut child = self.spawn(&channel).unwrap();
+            Executor::forward(child.stdout.take().unwrap(), Stream::Stdout, tx.clone());
+            Executor::forward(child.stderr.take().unwrap(), Stream::Stderr, tx.clone());
+            (child, 2)
+        };
+        if let Some(c) = &channel {
+            info!("[channel]listening on {}", c.path().display());
+            match c.open() {
+                Ok(f) => Executor::forward(f, Stream::Channel, tx),
+                Err(e) => warn!("[channel]{}", e)
+            };
+        }
         info!("[execution]executable file is running");
-        let mut s = String::new();
-        let l = self.notice.len();
         
         loop {
-            match buffer.read_line(&mut s) {
-                Ok(x) => {
-                    if x == 0 {
+            // the deferred switches and the fan curve, alongside the strategy
+            self.state_manager.tick();
+            let event = match rx.recv_timeout(WAIT_POLL_INTERVAL) {
+                Ok(e) => e,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.tick();
+                    continue;
+                },
+                Err(RecvTimeoutError::Disconnected) => break
+            };
+            let (stream, read_at, s) = match event {
+                Event::Line(stream, read_at, s) => (stream, read_at, s),
+                Event::Closed => {
+                    open_outputs -= 1;
+                    if open_outputs == 0 {
                         break;
                     }
-                    match Executor::check_process(&s) {
-                        Some(x) => {
-                            println!("now the progress is {:.2}", x);
-                            unsafe {
-                                PROGRESS = x;
-                                if x >= 100.0 {
-                                    crate::logger::STOP = true;
-                                }
-                            }
-                        }
-                        None => {}
-                    };
-                    if self.notice_index < l {
-                        if self.notice[self.notice_index].find(s.as_str()) {
-                            info!("[execution]hint:{} is matched", self.notice[self.notice_index].hint);
-                            self.notice[self.notice_index].act(self.state_manager);
-                            
-                            self.notice_index += 1;
-                        }
-                    }
-                    info!("[running] get a line\n *{}",s);
-                    //info!("[power] now the total power is {}", self.get_power());
-                },
-                Err(e) => {
-                    print!("{}",e);
-                    break;
+                    continue;
                 }
             };
-            s.clear(); // s must be clear,because new line will append to the original content
+            if let Some(capture) = &mut self.capture {
+                let at = self.state_manager.timeline().clock.at(read_at);
+                let progress = unsafe { PROGRESS };
+                capture.write(at, stream, progress, self.state_manager.current_state(), &s);
+            }
+            if stream.carries_hints() {
+                self.feed_line(&s);
+            } else if stream == Stream::Channel {
+                match Message::parse(&s) {
+                    Some(m) => self.feed_message(m),
+                    None => warn!("[channel]unknown message {}", s)
+                };
+            }
+            info!("[running] get a line from {}\n *{}", stream, s);
+            //info!("[power] now the total power is {}", self.get_power());
+            if let Some(r) = self.state_manager.refused() {
+                error!("[execution]the hardware refuses {}, the application is stopped", r);
+                let _ = child.kill();
+                break;
+            }
+        }
+        if self.running.is_some() {
+            warn!("[execution]the application ends before the running action finishes");
         }
+        match child.wait() {
+            Ok(status) => info!("[execution]the application exits with {}", status),
+            Err(e) => println!("{}", e)
+        };
     }
 }
 
@@ -171,8 +451,10 @@ mod test {
     use std::time::Duration;
 
     use super::*;
-    #[test]
-    /*fn test_action_generation_1() {
+    use crate::State;
+    use crate::timeline::Clock;
+    /*#[test]
+    fn test_action_generation_1() {
         let raw = r#"
         {
             "hint": "PCOL",
@@ -267,6 +549,59 @@ mod test {
         assert!(a.find("Prog= 80.22%"));
     }
     #[test]
+    fn test_phase() {
+        let raw = r#"
+        {
+            "phase": "update",
+            "action": [
+                {
+                    "GPU_Freq": 810
+                }
+            ]
+        }
+        "#;
+        let v = serde_json::from_str(raw).unwrap();
+        let a = Action::from(&v);
+        assert!(a.on_phase("update"));
+        assert!(!a.find("update"));
+        assert_eq!("(phase: update, action_set: [State{GPU_Freq: 810MHz,}])", format!("{}", a));
+    }
+    #[test]
+    fn test_trigger_kind() {
+        let v: Value = serde_json::from_str(r#"[{"phase": "update", "action": [{"GPU_Freq": 810}]}, {"hint": "PCOL", "action": []}]"#).unwrap();
+        let mut state_manager = StateManager::dry_run(State::new(Some(900), Some(390), Some(40), None), Clock::Virtual(Duration::ZERO));
+        let mut e = Executor::replay(&v, &mut state_manager);
+        e.feed_message(Message::Phase("update".to_string()));
+        e.feed_line("PCOL");
+        let kinds: Vec<&str> = e.state_manager().timeline().entries().iter().map(|x| x.kind).filter(|k| *k == "phase" || *k == "hint").collect();
+        assert_eq!(kinds, vec!["phase", "hint"]);
+    }
+    #[test]
+    fn test_wait_for_output() {
+        let raw = r#"
+        [
+            {
+                "hint": "PCOL",
+                "action": [
+                    {"GPU_Freq": 585},
+                    {"Wait": "output ~ READY", "Timeout": 1000},
+                    {"GPU_Freq": 795}
+                ]
+            }
+        ]
+        "#;
+        let v: Value = serde_json::from_str(raw).unwrap();
+        let start = State::new(Some(900), Some(390), Some(40), None);
+        let mut state_manager = StateManager::dry_run(start, Clock::Virtual(Duration::ZERO));
+        let mut e = Executor::replay(&v, &mut state_manager);
+        e.feed_line("PCOL");
+        e.feed_line("something else");
+        assert_eq!(e.state_manager().current_state().gpu_freq, Some(585));
+        e.feed_line("READY");
+        assert_eq!(e.state_manager().current_state().gpu_freq, Some(795));
+        assert!(e.running.is_none());
+    }
+    #[test]
     fn test_get_progress() {
         Executor::check_process("Prog= 12.22% aaaaa");
 
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+/// the named groups of the hint which triggered an action, when they are numbers
+pub type Captures = HashMap<String, f64>;
+
+/// a small arithmetic expression for the value of a knob, such as `gpu_freq + 75` or `max(current - 10%, 30)`
+/// `+75` and `-10%` are short for `current + 75` and `current - 10%`,
+/// `N%` is N percent of `current`, the value of the knob before the switch
+#[derive(Clone, Debug)]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Num(f64),
+    Var(String),
+    Percent(Box<Node>),
+    Neg(Box<Node>),
+    Bin(Box<Node>, char, Box<Node>),
+    Call(String, Vec<Node>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(f64),
+    Ident(String),
+    Sym(char),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let st = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let n: String = chars[st..i].iter().collect();
+            tokens.push(Token::Num(n.parse().map_err(|_| format!("bad number {} in {}", n, s))?));
+        } else if c.is_alphabetic() || c == '_' {
+            let st = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[st..i].iter().collect()));
+        } else if "+-*/%(),".contains(c) {
+            tokens.push(Token::Sym(c));
+            i += 1;
+        } else {
+            return Err(format!("unexpected {} in {}", c, s));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Sym(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn expr(&mut self) -> Result<Node, String> {
+        let mut left = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Sym(c)) if *c == '+' || *c == '-' => *c,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            left = Node::Bin(Box::new(left), op, Box::new(self.term()?));
+        }
+    }
+    fn term(&mut self) -> Result<Node, String> {
+        let mut left = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Sym(c)) if *c == '*' || *c == '/' => *c,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            left = Node::Bin(Box::new(left), op, Box::new(self.unary()?));
+        }
+    }
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.eat('-') {
+            return Ok(Node::Neg(Box::new(self.unary()?)));
+        }
+        let node = self.primary()?;
+        if self.eat('%') {
+            return Ok(Node::Percent(Box::new(node)));
+        }
+        Ok(node)
+    }
+    fn primary(&mut self) -> Result<Node, String> {
+        let token = self.peek().cloned().ok_or("the expression ends too early")?;
+        self.pos += 1;
+        match token {
+            Token::Num(x) => Ok(Node::Num(x)),
+            Token::Ident(name) => {
+                if !self.eat('(') {
+                    return Ok(Node::Var(name));
+                }
+                let mut args = vec![self.expr()?];
+                while self.eat(',') {
+                    args.push(self.expr()?);
+                }
+                if !self.eat(')') {
+                    return Err(format!("missing ) after the arguments of {}", name));
+                }
+                Ok(Node::Call(name, args))
+            },
+            Token::Sym('(') => {
+                let node = self.expr()?;
+                if !self.eat(')') {
+                    return Err("missing )".to_string());
+                }
+                Ok(node)
+            },
+            Token::Sym(c) => Err(format!("unexpected {}", c)),
+        }
+    }
+}
+
+impl Node {
+    fn eval(&self, vars: &dyn Fn(&str) -> Option<f64>) -> Result<f64, String> {
+        let var = |name: &str| vars(name).ok_or(format!("unknown value {}", name));
+        match self {
+            Node::Num(x) => Ok(*x),
+            Node::Var(name) => var(name),
+            Node::Percent(x) => Ok(x.eval(vars)? / 100.0 * var("current")?),
+            Node::Neg(x) => Ok(-x.eval(vars)?),
+            Node::Bin(a, op, b) => {
+                let (a, b) = (a.eval(vars)?, b.eval(vars)?);
+                match op {
+                    '+' => Ok(a + b),
+                    '-' => Ok(a - b),
+                    '*' => Ok(a * b),
+                    _ if b == 0.0 => Err("division by 0".to_string()),
+                    _ => Ok(a / b),
+                }
+            },
+            Node::Call(name, args) => {
+                let args = args.iter().map(|x| x.eval(vars)).collect::<Result<Vec<f64>, String>>()?;
+                match name.as_str() {
+                    "min" => Ok(args.into_iter().fold(f64::INFINITY, f64::min)),
+                    "max" => Ok(args.into_iter().fold(f64::NEG_INFINITY, f64::max)),
+                    _ => Err(format!("unknown function {}, use min or max", name)),
+                }
+            },
+        }
+    }
+}
+
+impl Expression {
+    pub fn parse(s: &str) -> Result<Expression, String> {
+        let s = s.trim();
+        let text = if s.starts_with('+') || s.starts_with('-') {
+            format!("current {}", s)
+        } else {
+            s.to_string()
+        };
+        let mut parser = Parser { tokens: tokenize(&text)?, pos: 0 };
+        let root = parser.expr()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(format!("can not understand the end of {}", s));
+        }
+        Ok(Expression { source: s.to_string(), root })
+    }
+    /// `vars` gives the value of a name, `current` included
+    pub fn eval(&self, vars: &dyn Fn(&str) -> Option<f64>) -> Result<f64, String> {
+        self.root.eval(vars)
+    }
+}
+
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn eval(s: &str) -> Result<f64, String> {
+        Expression::parse(s)?.eval(&|name| match name {
+            "current" => Some(600.0),
+            "power" => Some(1300.0),
+            "nb" => Some(192.0),
+            _ => None,
+        })
+    }
+    #[test]
+    fn test_relative() {
+        assert_eq!(eval("+75"), Ok(675.0));
+        assert_eq!(eval("-10%"), Ok(540.0));
+        assert_eq!(eval("current + 10 %"), Ok(660.0));
+    }
+    #[test]
+    fn test_expression() {
+        assert_eq!(eval("max(1500 - power, 100) * 2"), Ok(400.0));
+        assert_eq!(eval("10 - (nb / 64)"), Ok(7.0));
+        assert_eq!(eval("-(nb / 64)"), Ok(597.0));
+        assert!(eval("temperature + 1").is_err());
+        assert!(Expression::parse("1 +").is_err());
+        assert!(Expression::parse("(1 + 2").is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use crate::readback;
+use serde_json::Value;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// the fan speed of a state which hands the fans over to the curve, `"Fan_Speed": "curve"`
+pub const CURVE: &str = "curve";
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// what the fan speed follows
+#[derive(Clone, PartialEq, Debug)]
+pub enum Input {
+    /// the power of the cluster in W
+    Power,
+    /// the highest number printed by a shell command, in °C
+    Temperature(String),
+}
+
+/// a piecewise-linear map from the power or the temperature to the fan speed, written as
+/// `"fan_curve": {"input": "power", "points": [[800, 30], [1200, 60], [1500, 100]], "hysteresis": 50, "interval_ms": 1000}`
+/// or `"input": "temperature", "temperature_command": "..."`, the speed is flat beyond the first and the last points
+#[derive(Clone, PartialEq, Debug)]
+pub struct FanCurve {
+    points: Vec<(f64, usize)>,
+    pub input: Input,
+    /// how far the input falls below the point of the current speed before the fans slow down
+    pub hysteresis: f64,
+    /// how often the curve is evaluated
+    pub interval: Duration,
+}
+
+impl From<&Value> for FanCurve {
+    fn from(v: &Value) -> Self {
+        let obj = v.as_object().expect("fan_curve must be an object");
+        let mut points: Vec<(f64, usize)> = obj.get("points")
+            .and_then(|x| x.as_array())
+            .expect("fan_curve needs points like [[800, 30], [1500, 100]]")
+            .iter()
+            .map(|p| match p.as_array().map(|a| a.as_slice()) {
+                Some([x, s]) => (x.as_f64().expect("need a number"), (s.as_u64().expect("need a number[0-100]") as usize).min(100)),
+                _ => panic!("a point of fan_curve must be [input, fan speed]"),
+            })
+            .collect();
+        assert!(!points.is_empty(), "fan_curve needs at least one point");
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let input = match obj.get("input").map(|x| x.as_str().expect("the input of fan_curve must be a string")) {
+            None | Some("power") => Input::Power,
+            Some("temperature") => Input::Temperature(obj.get("temperature_command")
+                .and_then(|x| x.as_str())
+                .expect("a fan curve of the temperature needs a temperature_command")
+                .to_string()),
+            Some(x) => panic!("unknown input {} of fan_curve, need power or temperature", x),
+        };
+        FanCurve {
+            points,
+            input,
+            hysteresis: obj.get("hysteresis").map_or(0.0, |x| x.as_f64().expect("hysteresis must be a number")),
+            interval: obj.get("interval_ms")
+                .map_or(DEFAULT_INTERVAL, |x| Duration::from_millis(x.as_u64().expect("need a number of milisecond"))),
+        }
+    }
+}
+
+impl FanCurve {
+    /// the fan speed of the curve at `x`
+    pub fn speed_at(&self, x: f64) -> usize {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+        if x <= first.0 {
+            return first.1;
+        }
+        if x >= last.0 {
+            return last.1;
+        }
+        let i = self.points.iter().position(|p| p.0 > x).unwrap();
+        let ((x0, s0), (x1, s1)) = (self.points[i - 1], self.points[i]);
+        (s0 as f64 + (s1 as f64 - s0 as f64) * (x - x0) / (x1 - x0)).round() as usize
+    }
+    /// the new fan speed at `x`, `None` to keep `current`
+    /// the fans speed up as soon as the curve rises, and slow down once `x` is `hysteresis` below the lower speed
+    pub fn next(&self, x: f64, current: Option<usize>) -> Option<usize> {
+        let up = self.speed_at(x);
+        let current = match current {
+            Some(c) => c,
+            None => return Some(up),
+        };
+        let down = self.speed_at(x + self.hysteresis);
+        if up > current {
+            Some(up)
+        } else if down < current {
+            Some(down)
+        } else {
+            None
+        }
+    }
+    /// the input of the curve now, `power` is the power of the cluster in W
+    pub fn read(&self, power: usize) -> Result<f64, String> {
+        let command = match &self.input {
+            Input::Power => return Ok(power as f64),
+            Input::Temperature(c) => c,
+        };
+        let output = process::Command::new("sh").arg("-c").arg(command).output()
+            .map_err(|e| format!("can not run {}: {}", command, e))?;
+        readback::numbers(&String::from_utf8_lossy(&output.stdout))
+            .into_iter()
+            .reduce(f64::max)
+            .ok_or_else(|| format!("{} prints no temperature", command))
+    }
+}
+
+/// the last temperature read by the command of a curve, the command runs every interval in a thread of its own
+/// so that a slow one never holds up the output of the application, it stops once the sensor is dropped
+pub struct Sensor {
+    latest: Arc<Mutex<Option<Result<f64, String>>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Sensor {
+    pub fn start(curve: &FanCurve) -> Sensor {
+        let latest = Arc::new(Mutex::new(None));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (l, s, c) = (Arc::clone(&latest), Arc::clone(&stop), curve.clone());
+        thread::spawn(move || {
+            while !s.load(Ordering::Relaxed) {
+                let x = c.read(0);
+                *l.lock().unwrap() = Some(x);
+                thread::sleep(c.interval);
+            }
+        });
+        Sensor { latest, stop }
+    }
+    /// the last reading, `None` until the command has printed once
+    pub fn latest(&self) -> Option<Result<f64, String>> {
+        self.latest.lock().unwrap().clone()
+    }
+}
+
+impl Drop for Sensor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_fan_curve() {
+        let v = serde_json::from_str(r#"{"points": [[1500, 100], [800, 30], [1200, 60]], "hysteresis": 100}"#).unwrap();
+        let curve = FanCurve::from(&v);
+        assert_eq!(curve.speed_at(500.0), 30);
+        assert_eq!(curve.speed_at(1000.0), 45);
+        assert_eq!(curve.speed_at(1350.0), 80);
+        assert_eq!(curve.speed_at(2000.0), 100);
+        assert_eq!(curve.next(1000.0, None), Some(45));
+        assert_eq!(curve.next(1350.0, Some(45)), Some(80));
+        // within the hysteresis the fans keep their speed
+        assert_eq!(curve.next(1300.0, Some(80)), None);
+        assert_eq!(curve.next(1100.0, Some(80)), Some(60));
+        let v = serde_json::from_str(r#"{"input": "temperature", "temperature_command": "echo 61; echo 67", "points": [[60, 40]]}"#).unwrap();
+        assert_eq!(FanCurve::from(&v).read(1000), Ok(67.0));
+    }
+    #[test]
+    fn test_sensor() {
+        let v = serde_json::from_str(r#"{"input": "temperature", "temperature_command": "sleep 0.2; echo 67", "points": [[60, 40]], "interval_ms": 50}"#).unwrap();
+        let t = std::time::Instant::now();
+        let sensor = Sensor::start(&FanCurve::from(&v));
+        // the command runs in the background
+        assert_eq!(sensor.latest(), None);
+        assert!(t.elapsed() < Duration::from_millis(100));
+        while sensor.latest().is_none() {
+            assert!(t.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(sensor.latest(), Some(Ok(67.0)));
+    }
+}
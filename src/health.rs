@@ -0,0 +1,213 @@
+use crate::State;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::thread::sleep;
+use std::time::Duration;
+
+const DEFAULT_ATTEMPTS: usize = 3;
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(200);
+const DEFAULT_MAX_FAILURES: usize = 3;
+/// the commands sent to the whole cluster are counted under this name, it is never down
+pub const CLUSTER: &str = "cluster";
+
+/// what is done once a node keeps failing
+#[derive(Clone, PartialEq, Debug)]
+pub enum OnFailure {
+    /// leave the node out and go on with the others
+    Continue,
+    /// set this state on the whole cluster and keep it until the end of the run
+    SafeState(Box<State>),
+    /// stop the application
+    Abort,
+}
+
+/// how the commands sent to the hardware are retried, written as
+/// `"retry": {"attempts": 3, "backoff_ms": 200, "max_failures": 3, "on_failure": "continue"}`
+/// or `"on_failure": "safe_state", "safe_state": {"Fan_Speed": 100}` or `"on_failure": "abort"`
+#[derive(Clone, PartialEq, Debug)]
+pub struct Retry {
+    /// how many times a command is tried at most
+    pub attempts: usize,
+    /// the wait before the second try, doubled before each next one
+    pub backoff: Duration,
+    /// a node is down after this many failed commands in a row
+    pub max_failures: usize,
+    pub on_failure: OnFailure,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: DEFAULT_ATTEMPTS,
+            backoff: DEFAULT_BACKOFF,
+            max_failures: DEFAULT_MAX_FAILURES,
+            on_failure: OnFailure::Continue,
+        }
+    }
+}
+
+impl From<&Value> for Retry {
+    fn from(v: &Value) -> Self {
+        let obj = v.as_object().expect("retry must be an object");
+        let count = |key: &str, default: usize| obj.get(key)
+            .map_or(default, |x| x.as_u64().unwrap_or_else(|| panic!("{} must be a number", key)) as usize);
+        let on_failure = match obj.get("on_failure").map(|x| x.as_str().expect("on_failure must be a string")) {
+            None | Some("continue") => OnFailure::Continue,
+            Some("abort") => OnFailure::Abort,
+            // the fans at full speed keep every node safe whatever the frequencies are
+            Some("safe_state") => OnFailure::SafeState(Box::new(obj.get("safe_state")
+                .map_or(State::new(None, None, Some(100), None), State::from))),
+            Some(x) => panic!("unknown on_failure {}, need continue, safe_state or abort", x),
+        };
+        Retry {
+            attempts: count("attempts", DEFAULT_ATTEMPTS).max(1),
+            backoff: obj.get("backoff_ms")
+                .map_or(DEFAULT_BACKOFF, |x| Duration::from_millis(x.as_u64().expect("need a number of milisecond"))),
+            max_failures: count("max_failures", DEFAULT_MAX_FAILURES).max(1),
+            on_failure,
+        }
+    }
+}
+
+impl Retry {
+    /// try `f` until it succeeds or the attempts run out, the last result and the number of tries are returned
+    pub fn run<F: FnMut() -> Result<(), String>>(&self, mut f: F) -> (Result<(), String>, usize) {
+        let mut wait = self.backoff;
+        let mut attempt = 1;
+        loop {
+            let result = f();
+            if result.is_ok() || attempt >= self.attempts {
+                return (result, attempt);
+            }
+            sleep(wait);
+            wait *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+/// the node a command is sent to, `SETFREQ GPU@node1.0 825` goes to node1
+pub fn node_of(command: &str) -> &str {
+    match command.split_whitespace().nth(1).and_then(|d| d.split_once('@')) {
+        Some((_, n)) => n.split('.').next().unwrap(),
+        None => CLUSTER,
+    }
+}
+
+/// a command to the whole cluster sent to `node` only, `SETFREQ GPU 825` becomes `SETFREQ GPU@node1 825`
+pub fn on_node(command: &str, node: &str) -> Option<String> {
+    let mut words: Vec<String> = command.split_whitespace().map(|w| w.to_string()).collect();
+    let device = words.get_mut(1)?;
+    *device = format!("{}@{}", device, node);
+    Some(words.join(" "))
+}
+
+/// the commands sent to one node
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct NodeHealth {
+    pub commands: usize,
+    /// commands which only succeed after a retry
+    pub retried: usize,
+    pub failed: usize,
+    /// failed commands since the last one which succeeds
+    pub consecutive: usize,
+    pub last_error: Option<String>,
+    /// no more commands are sent to the node
+    pub down: bool,
+}
+
+/// the health of every node commands are sent to
+#[derive(Default)]
+pub struct Health {
+    nodes: BTreeMap<String, NodeHealth>,
+    /// nodes which are down and not yet handled by the failure policy
+    newly_down: Vec<String>,
+}
+
+impl Health {
+    /// count a command sent to `node` after `attempts` tries
+    pub fn record(&mut self, node: &str, attempts: usize, result: &Result<(), String>, max_failures: usize) {
+        let h = self.nodes.entry(node.to_string()).or_default();
+        h.commands += 1;
+        match result {
+            Ok(()) => {
+                if attempts > 1 {
+                    h.retried += 1;
+                }
+                h.consecutive = 0;
+            }
+            Err(e) => {
+                h.failed += 1;
+                h.consecutive += 1;
+                h.last_error = Some(e.clone());
+                // the failures of the whole cluster are found node by node
+                if h.consecutive >= max_failures && !h.down && node != CLUSTER {
+                    h.down = true;
+                    self.newly_down.push(node.to_string());
+                }
+            }
+        }
+    }
+    pub fn is_down(&self, node: &str) -> bool {
+        self.nodes.get(node).is_some_and(|h| h.down)
+    }
+    pub fn get(&self, node: &str) -> Option<&NodeHealth> {
+        self.nodes.get(node)
+    }
+    /// the nodes gone down since the last call
+    pub fn take_down(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.newly_down)
+    }
+    pub fn to_value(&self) -> Value {
+        let mut m = Map::new();
+        for (node, h) in &self.nodes {
+            m.insert(node.clone(), json!({
+                "commands": h.commands,
+                "retried": h.retried,
+                "failed": h.failed,
+                "last_error": h.last_error,
+                "down": h.down,
+            }));
+        }
+        Value::Object(m)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::Knob;
+    #[test]
+    fn test_retry() {
+        assert_eq!(node_of("SETFREQ GPU@node1.0 825"), "node1");
+        assert_eq!(node_of("SETSPEED FAN 80"), CLUSTER);
+        assert_eq!(on_node("SETSPEED FAN 80", "node2").as_deref(), Some("SETSPEED FAN@node2 80"));
+        let v = serde_json::from_str(r#"
+        {"attempts": 3, "backoff_ms": 0, "max_failures": 2, "on_failure": "safe_state", "safe_state": {"Fan_Speed": 90}}
+        "#).unwrap();
+        let retry = Retry::from(&v);
+        match &retry.on_failure {
+            OnFailure::SafeState(s) => assert_eq!(s.get(Knob::FanSpeed), Some(90)),
+            x => panic!("{:?}", x),
+        }
+        // a hiccup is hidden by the second try
+        let mut tries = 0;
+        let (result, attempts) = retry.run(|| { tries += 1; if tries < 2 { Err("timeout".to_string()) } else { Ok(()) } });
+        assert_eq!((result.is_ok(), attempts), (true, 2));
+        let mut health = Health::default();
+        health.record("node1", attempts, &result, retry.max_failures);
+        let broken = retry.run(|| Err("no route to host".to_string()));
+        assert_eq!(broken.1, 3);
+        health.record("node2", broken.1, &broken.0, retry.max_failures);
+        assert!(!health.is_down("node2"));
+        health.record("node2", broken.1, &broken.0, retry.max_failures);
+        assert!(health.is_down("node2") && !health.is_down("node1"));
+        assert_eq!(health.take_down(), vec!["node2".to_string()]);
+        assert!(health.take_down().is_empty());
+        assert_eq!(health.get("node1").unwrap().retried, 1);
+        assert_eq!(health.to_value()["node2"]["last_error"], "no route to host");
+        health.record(CLUSTER, broken.1, &broken.0, retry.max_failures);
+        health.record(CLUSTER, broken.1, &broken.0, retry.max_failures);
+        assert!(!health.is_down(CLUSTER) && health.take_down().is_empty());
+    }
+}
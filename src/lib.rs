@@ -2,7 +2,27 @@ pub mod state;
 pub mod execute;
 pub mod prepare;
 pub mod logger;
+pub mod timeline;
+pub mod replay;
+pub mod capture;
+pub mod lines;
+pub mod channel;
+pub mod tempdir;
+pub mod ramp;
+pub mod step;
+pub mod expr;
+pub mod config;
+pub mod lint;
+pub mod stability;
+pub mod report;
+pub mod baseline;
+pub mod readback;
+pub mod selftest;
+pub mod health;
+pub mod envelope;
+pub mod fan;
+pub mod shell;
 pub use state::{StateManager, State};
 pub use execute::Executor;
-pub use prepare::Preparer;
+pub use prepare::{Preparer, PrepStep};
 pub use logger::PowerLogger;
@@ -0,0 +1,92 @@
+use std::io::{self, BufRead, BufReader, Read};
+
+/// longest line handed out, longer ones are cut into pieces
+pub const DEFAULT_MAX_LINE: usize = 64 * 1024;
+
+/// reads the output of an application line by line
+/// lines end with `\n` or `\r` (so a progress bar refreshed by `\r` gives one line per refresh),
+/// empty lines are skipped, invalid UTF-8 is replaced instead of failing,
+/// and no line is longer than `max_line` bytes
+pub struct LineReader<R> {
+    reader: BufReader<R>,
+    buf: Vec<u8>,
+    max_line: usize,
+}
+
+impl<R: Read> LineReader<R> {
+    pub fn new(r: R) -> LineReader<R> {
+        LineReader {
+            reader: BufReader::new(r),
+            buf: Vec::new(),
+            max_line: DEFAULT_MAX_LINE,
+        }
+    }
+    pub fn with_max_line(mut self, max_line: usize) -> LineReader<R> {
+        self.max_line = max_line.max(1);
+        self
+    }
+    /// the next line without its ending, `None` at the end of the output
+    pub fn next_line(&mut self) -> io::Result<Option<String>> {
+        self.buf.clear();
+        loop {
+            let (done, used) = {
+                let available = match self.reader.fill_buf() {
+                    Ok(x) => x,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                if available.is_empty() {
+                    break;
+                }
+                let room = self.max_line - self.buf.len();
+                let end = available.iter().take(room).position(|&b| b == b'\n' || b == b'\r');
+                match end {
+                    Some(i) => {
+                        self.buf.extend_from_slice(&available[..i]);
+                        (!self.buf.is_empty(), i + 1)
+                    },
+                    None => {
+                        let n = available.len().min(room);
+                        self.buf.extend_from_slice(&available[..n]);
+                        (self.buf.len() >= self.max_line, n)
+                    }
+                }
+            };
+            self.reader.consume(used);
+            if done {
+                break;
+            }
+        }
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&self.buf).into_owned()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn read_all(r: &mut LineReader<&[u8]>) -> Vec<String> {
+        let mut lines = vec![];
+        while let Some(s) = r.next_line().unwrap() {
+            lines.push(s);
+        }
+        lines
+    }
+    #[test]
+    fn test_split_on_carriage_return() {
+        let mut r = LineReader::new("Prog= 10.00%\rProg= 20.00%\r\nPCOL\n\nend".as_bytes());
+        assert_eq!(read_all(&mut r), vec!["Prog= 10.00%", "Prog= 20.00%", "PCOL", "end"]);
+    }
+    #[test]
+    fn test_invalid_utf8_is_kept() {
+        let mut r = LineReader::new(&b"ab\xffcd\nPCOL\n"[..]);
+        assert_eq!(read_all(&mut r), vec!["ab\u{fffd}cd", "PCOL"]);
+    }
+    #[test]
+    fn test_long_line_is_cut() {
+        let mut r = LineReader::new("abcdefg\nhi\n".as_bytes()).with_max_line(3);
+        assert_eq!(read_all(&mut r), vec!["abc", "def", "g", "hi"]);
+    }
+}
@@ -0,0 +1,577 @@
+use crate::expr::Expression;
+use crate::fan;
+use crate::state::Knob;
+use crate::step::{Cmp, Condition};
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+/// keys of a state which are not knobs
+const STATE_KEYS: [&str; 4] = ["Time", "Node", "GPUs", "Fans"];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Level {
+    /// the run would panic or do something else than written
+    Error,
+    /// the run works but probably not as meant
+    Warning,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Issue {
+    pub level: Level,
+    /// where in the application file, such as `strategy[1].action[0]`
+    pub place: String,
+    pub message: String,
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let level = match self.level {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", level, self.place, self.message)
+    }
+}
+
+/// a server of the cluster file, for checking targets
+struct Server {
+    name: String,
+    gpus: Option<u64>,
+    fans: Option<u64>,
+}
+
+/// how many indices of a kind a server has
+type Count = fn(&Server) -> Option<u64>;
+
+/// the literal text at the start of a hint, which every line it matches contains,
+/// empty when the hint starts with something else or has alternatives
+fn required_literal(pattern: &str) -> String {
+    let mut literal = String::new();
+    if pattern.contains('|') {
+        return literal;
+    }
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(x) if x.is_ascii_punctuation() => literal.push(x),
+                _ => break,
+            },
+            // the character before can be missing
+            '?' | '*' | '{' => {
+                literal.pop();
+                break;
+            },
+            '.' | '^' | '$' | '+' | '(' | ')' | '[' | ']' | '}' => break,
+            _ => literal.push(c),
+        }
+    }
+    literal
+}
+
+/// the problems of the `"Node"`, `"GPUs"` and `"Fans"` of a state
+fn target_issues(servers: &[Server], obj: &Map<String, Value>) -> Vec<(Level, String)> {
+    let found: Vec<&Server> = match obj.get("Node").map(|x| x.as_str()) {
+        None => servers.iter().collect(),
+        Some(Some(n)) => match servers.iter().find(|s| s.name == n) {
+            Some(s) => vec![s],
+            None => return vec![(Level::Error, format!("there is no enabled server {} in the cluster file", n))],
+        },
+        Some(None) => return vec![(Level::Error, "Node must be the name of a server".to_string())],
+    };
+    let counts: [(&str, Count); 2] = [("GPUs", |s| s.gpus), ("Fans", |s| s.fans)];
+    let mut messages = vec![];
+    for (key, count) in counts {
+        let indices = match obj.get(key).map(|x| x.as_array()) {
+            None => continue,
+            Some(Some(x)) => x,
+            Some(None) => {
+                messages.push((Level::Error, format!("{} must be an array of index", key)));
+                continue;
+            }
+        };
+        for i in indices {
+            match i.as_u64() {
+                None => messages.push((Level::Error, format!("an index of {} must be a number", key))),
+                Some(i) => for s in &found {
+                    if count(s).is_some_and(|c| i >= c) {
+                        messages.push((Level::Error, format!("{} has no index {} in {}", s.name, i, key)));
+                    }
+                },
+            }
+        }
+    }
+    messages
+}
+
+struct Linter {
+    issues: Vec<Issue>,
+    /// the lowest and highest value of the frequency table of a knob
+    range: HashMap<Knob, (u64, u64)>,
+    servers: Option<Vec<Server>>,
+    /// whether the application has a fan curve to hand the fans over to
+    fan_curve: bool,
+}
+
+impl Linter {
+    fn report(&mut self, level: Level, place: &str, message: String) {
+        self.issues.push(Issue { level, place: place.to_string(), message });
+    }
+    fn value(&mut self, place: &str, knob: Knob, x: u64) {
+        if knob == Knob::FanSpeed && x > 100 {
+            self.report(Level::Error, place, format!("{} {} is out of 0-100", knob.key(), x));
+        }
+        if let Some((lo, hi)) = self.range.get(&knob).copied() {
+            if x < lo || x > hi {
+                self.report(Level::Warning, place,
+                    format!("{} {}{} is out of the frequency table {}-{}", knob.key(), x, knob.unit(), lo, hi));
+            }
+        }
+    }
+    fn ramp(&mut self, place: &str, knob: Knob, obj: &Map<String, Value>) {
+        match obj.get("to").and_then(|x| x.as_u64()) {
+            Some(x) => self.value(place, knob, x),
+            None => self.report(Level::Error, place, format!("the ramp of {} needs a number \"to\"", knob.key())),
+        }
+        match obj.get("over_ms").map(|x| x.as_u64()) {
+            None | Some(Some(0)) => self.report(Level::Warning, place,
+                format!("the ramp of {} lasts 0ms, it jumps at once", knob.key())),
+            Some(None) => self.report(Level::Error, place, "over_ms must be a number of milisecond".to_string()),
+            _ => {},
+        }
+        if let Some(x) = obj.get("step") {
+            if x.as_u64().unwrap_or(0) == 0 {
+                self.report(Level::Error, place, format!("the step of the ramp of {} must be a positive number", knob.key()));
+            }
+        }
+        match obj.get("curve").map(|x| x.as_str()) {
+            None | Some(Some("linear")) | Some(Some("exp")) => {},
+            Some(_) => self.report(Level::Error, place, "curve must be linear or exp".to_string()),
+        }
+    }
+    fn target(&mut self, place: &str, obj: &Map<String, Value>) {
+        let messages = match &self.servers {
+            Some(servers) => target_issues(servers, obj),
+            None => return,
+        };
+        for (level, message) in messages {
+            self.report(level, place, message);
+        }
+    }
+    /// `start` is the start state, whose base knobs are needed for resetting
+    fn state(&mut self, place: &str, v: &Value, start: bool) {
+        let obj = match v.as_object() {
+            Some(x) => x,
+            None => return self.report(Level::Error, place, "a state must be an object".to_string()),
+        };
+        for key in obj.keys() {
+            if Knob::from_key(key).is_none() && !STATE_KEYS.contains(&key.as_str()) {
+                self.report(Level::Warning, place, format!("{} is not a knob, it is ignored", key));
+            }
+        }
+        for knob in Knob::ALL {
+            match obj.get(knob.key()) {
+                None => {},
+                Some(Value::Object(x)) => self.ramp(place, knob, x),
+                Some(Value::String(x)) if knob == Knob::FanSpeed && x == fan::CURVE && !self.fan_curve => {
+                    self.report(Level::Error, place, "Fan_Speed follows the curve but there is no fan_curve".to_string());
+                },
+                Some(Value::String(x)) if knob == Knob::FanSpeed && x == fan::CURVE => {},
+                Some(Value::String(x)) => if let Err(e) = Expression::parse(x) {
+                    self.report(Level::Error, place, format!("can not parse {} of {}: {}", x, knob.key(), e));
+                },
+                Some(x) => match x.as_u64() {
+                    Some(x) => self.value(place, knob, x),
+                    None => self.report(Level::Error, place, format!("{} needs a positive whole number", knob.key())),
+                },
+            }
+        }
+        if start {
+            for knob in &Knob::ALL[..3] {
+                // the fans of a start state can follow the curve instead
+                let curve = |x: &Value| *knob == Knob::FanSpeed && x.as_str() == Some(fan::CURVE);
+                if !obj.get(knob.key()).is_some_and(|x| x.is_u64() || curve(x)) {
+                    self.report(Level::Error, place, format!("the start state needs a number for {}, reset uses it", knob.key()));
+                }
+            }
+        }
+        if obj.get("Time").is_some_and(|x| !x.is_u64()) {
+            self.report(Level::Error, place, "Time must be a number of milisecond".to_string());
+        }
+        self.target(place, obj);
+    }
+    fn fan_curve(&mut self, v: &Value) {
+        let points = match v["points"].as_array() {
+            Some(x) if !x.is_empty() => x,
+            _ => return self.report(Level::Error, "fan_curve", "the fan curve needs points like [[800, 30], [1500, 100]]".to_string()),
+        };
+        for (i, p) in points.iter().enumerate() {
+            match p.as_array().map(|a| a.as_slice()) {
+                Some([x, s]) if x.is_number() && s.as_u64().is_some_and(|s| s <= 100) => {},
+                _ => self.report(Level::Error, &format!("fan_curve.points[{}]", i), "a point must be [input, fan speed[0-100]]".to_string()),
+            }
+        }
+        match v["input"].as_str() {
+            None | Some("power") => {},
+            Some("temperature") if v["temperature_command"].is_string() => {},
+            Some("temperature") => self.report(Level::Error, "fan_curve", "a fan curve of the temperature needs a temperature_command".to_string()),
+            Some(x) => self.report(Level::Error, "fan_curve", format!("unknown input {}, need power or temperature", x)),
+        }
+    }
+    /// a field of `obj` which must be a whole number when it is there
+    fn number(&mut self, place: &str, obj: &Map<String, Value>, key: &str) {
+        if obj.get(key).is_some_and(|x| !x.is_u64()) {
+            self.report(Level::Error, place, format!("{} must be a positive whole number", key));
+        }
+    }
+    fn preparation(&mut self, v: &Value) {
+        let steps = match v.as_array() {
+            Some(x) => x,
+            None => return self.report(Level::Error, "preparation", "the preparation must be an array of steps".to_string()),
+        };
+        for (i, s) in steps.iter().enumerate() {
+            let place = format!("preparation[{}]", i);
+            match s {
+                Value::String(x) if x == "Cool" || x == "WaitStable" => {},
+                Value::Object(obj) if obj.contains_key("Cool") => self.number(&place, obj, "Cool"),
+                Value::Object(obj) if obj.contains_key("Warm") => {
+                    if !obj["Warm"].is_string() {
+                        self.report(Level::Error, &place, "Warm must be a shell command".to_string());
+                    }
+                    if let Some(x) = obj.get("State") {
+                        self.state(&place, x, false);
+                    }
+                },
+                Value::Object(obj) if obj.contains_key("Hold") => self.state(&place, &obj["Hold"], false),
+                Value::Object(obj) if obj.contains_key("WaitStable") => {},
+                _ => self.report(Level::Error, &place, "unknown preparation step, use Cool, Warm, Hold or WaitStable".to_string()),
+            }
+        }
+    }
+    fn envelope(&mut self, v: &Value) {
+        let obj = match v.as_object() {
+            Some(x) => x,
+            None => return self.report(Level::Error, "envelope", "the envelope must be an object".to_string()),
+        };
+        if let Some(r) = obj.get("ranges") {
+            let ranges = match r.as_object() {
+                Some(x) => x,
+                None => return self.report(Level::Error, "envelope", "the ranges must be an object".to_string()),
+            };
+            for (key, range) in ranges {
+                if Knob::from_key(key).is_none() {
+                    self.report(Level::Error, "envelope", format!("{} is not a knob", key));
+                }
+                match range.as_array().map(|a| a.iter().map(|x| x.as_u64()).collect::<Vec<_>>()).as_deref() {
+                    Some([Some(min), Some(max)]) if min > max => self.report(Level::Error, "envelope",
+                        format!("the range of {} is empty, {} is above {}", key, min, max)),
+                    Some([Some(_), Some(_)]) => {},
+                    _ => self.report(Level::Error, "envelope", format!("the range of {} must be [min, max]", key)),
+                }
+            }
+        }
+        match obj.get("min_fan_speed").map(|x| x.as_u64()) {
+            Some(Some(x)) if x > 100 => self.report(Level::Error, "envelope", format!("min_fan_speed {} is out of 0-100", x)),
+            Some(None) => self.report(Level::Error, "envelope", "min_fan_speed must be a positive whole number".to_string()),
+            _ => {},
+        }
+        self.number("envelope", obj, "max_switches_per_second");
+        if obj.get("max_switches_per_second").and_then(|x| x.as_u64()) == Some(0) {
+            self.report(Level::Error, "envelope", "max_switches_per_second must be at least 1".to_string());
+        }
+        self.number("envelope", obj, "min_dwell_ms");
+    }
+    fn retry(&mut self, v: &Value) {
+        let obj = match v.as_object() {
+            Some(x) => x,
+            None => return self.report(Level::Error, "retry", "retry must be an object".to_string()),
+        };
+        for key in ["attempts", "backoff_ms", "max_failures"] {
+            self.number("retry", obj, key);
+        }
+        match obj.get("on_failure").map(|x| x.as_str()) {
+            None | Some(Some("continue")) | Some(Some("abort")) => {},
+            Some(Some("safe_state")) => if let Some(x) = obj.get("safe_state") {
+                self.state("retry.safe_state", x, false);
+            },
+            _ => self.report(Level::Error, "retry", "on_failure must be continue, safe_state or abort".to_string()),
+        }
+    }
+    fn readback(&mut self, v: &Value) {
+        let obj = match v.as_object() {
+            Some(x) => x,
+            None => return self.report(Level::Error, "readback", "readback must be an object".to_string()),
+        };
+        match obj.get("commands").map(|x| x.as_object()) {
+            None => {},
+            Some(None) => self.report(Level::Error, "readback", "the commands must be an object".to_string()),
+            Some(Some(commands)) => for (key, command) in commands {
+                if Knob::from_key(key).is_none() {
+                    self.report(Level::Error, "readback", format!("{} is not a knob", key));
+                }
+                if !command.is_string() {
+                    self.report(Level::Error, "readback", format!("the command of {} must be a string", key));
+                }
+            },
+        }
+        let tolerance = match obj.get("tolerance") {
+            None => true,
+            Some(Value::String(s)) => s.trim().trim_end_matches('%').trim().parse::<f64>().is_ok(),
+            Some(x) => x.is_number(),
+        };
+        if !tolerance {
+            self.report(Level::Error, "readback", "need a tolerance like \"5%\"".to_string());
+        }
+        for key in ["verify", "fail_run"] {
+            if obj.get(key).is_some_and(|x| !x.is_boolean()) {
+                self.report(Level::Error, "readback", format!("{} must be true or false", key));
+            }
+        }
+        self.number("readback", obj, "retries");
+        self.number("readback", obj, "retry_ms");
+    }
+    /// `reached` is the progress which earlier waits have already seen
+    fn wait(&mut self, place: &str, condition: &Condition, reached: &mut f64) {
+        if let Condition::Progress(cmp, x) = condition {
+            let never = match cmp {
+                Cmp::Greater => *x >= 100.0,
+                Cmp::GreaterEqual => *x > 100.0,
+                Cmp::Less => *x <= *reached,
+                Cmp::LessEqual => *x < *reached,
+            };
+            if never {
+                self.report(Level::Warning, place, format!("{} can never hold, it always times out", condition));
+            } else if *cmp == Cmp::Greater || *cmp == Cmp::GreaterEqual {
+                *reached = reached.max(*x);
+            }
+        }
+    }
+    fn strategy(&mut self, v: &Value) {
+        let actions = match v.as_array() {
+            Some(x) => x,
+            None => return self.report(Level::Error, "strategy", "the strategy must be an array of action".to_string()),
+        };
+        let mut hints: Vec<(String, Regex)> = vec![];
+        let mut reached = 0.0;
+        for (i, a) in actions.iter().enumerate() {
+            let place = format!("strategy[{}]", i);
+            let obj = match a.as_object() {
+                Some(x) => x,
+                None => {
+                    self.report(Level::Error, &place, "an action must be an object".to_string());
+                    continue;
+                }
+            };
+            match obj.get("hint").map(|h| h.as_str().map(Regex::new)) {
+                Some(Some(Ok(re))) => {
+                    let text = re.as_str();
+                    let literal = required_literal(text);
+                    // only literal text is compared, other overlaps such as `W\w` before `WR` are not found
+                    let earlier = hints.iter().find(|(_, r)| {
+                        let pattern = r.as_str();
+                        pattern == text
+                            || (regex::escape(text) == text && r.is_match(text))
+                            || (!pattern.is_empty() && regex::escape(pattern) == pattern && literal.contains(pattern))
+                    });
+                    if let Some((p, _)) = earlier {
+                        let message = format!(
+                            "the lines of the hint {} also match the hint of {1}, the actions wait in order, so such a line fires {1} while it still waits",
+                            text, p,
+                        );
+                        self.report(Level::Warning, &place, message);
+                    }
+                    hints.push((place.clone(), re));
+                },
+                Some(Some(Err(e))) => self.report(Level::Error, &place, format!("invalid hint: {}", e)),
+                Some(None) => self.report(Level::Error, &place, "hint must be a string".to_string()),
+                None if obj.get("phase").is_none() => self.report(Level::Error, &place, "an action needs a hint or a phase".to_string()),
+                None => {},
+            }
+            let steps = match obj.get("action").and_then(|x| x.as_array()) {
+                Some(x) => x,
+                None => {
+                    self.report(Level::Error, &place, "action must be an array of steps".to_string());
+                    continue;
+                }
+            };
+            for (j, s) in steps.iter().enumerate() {
+                let place = format!("{}.action[{}]", place, j);
+                if let Some(x) = s.get("Wait") {
+                    match x.as_str().map(Condition::parse) {
+                        Some(Ok(c)) => self.wait(&place, &c, &mut reached),
+                        Some(Err(e)) => self.report(Level::Error, &place, e),
+                        None => self.report(Level::Error, &place, "Wait must be a string".to_string()),
+                    }
+                } else if s.get("Command").is_some() || s.get("Shell").is_some() {
+                    continue;
+                } else {
+                    self.state(&place, s, false);
+                }
+            }
+        }
+    }
+}
+
+/// check a resolved application file before a run, against the servers of `cluster` when it is given
+pub fn lint(app: &Value, cluster: Option<&Value>) -> Vec<Issue> {
+    let mut range = HashMap::new();
+    if let Some(t) = app.get("frequency_table").and_then(|x| x.as_object()) {
+        for (key, values) in t {
+            let values: Vec<u64> = values.as_array().map(|a| a.iter().filter_map(|x| x.as_u64()).collect()).unwrap_or_default();
+            if let (Some(knob), Some(lo), Some(hi)) = (Knob::from_key(key), values.iter().min(), values.iter().max()) {
+                range.insert(knob, (*lo, *hi));
+            }
+        }
+    }
+    let servers = cluster.and_then(|c| c["servers"].as_array()).map(|a| a.iter()
+        .filter(|s| s["enabled"].as_bool().unwrap_or(true))
+        .filter_map(|s| Some(Server {
+            name: s["name"].as_str()?.to_string(),
+            gpus: s["gpu"]["count"].as_u64(),
+            fans: s["fan"]["count"].as_u64(),
+        }))
+        .collect());
+    let mut linter = Linter { issues: vec![], range, servers, fan_curve: app.get("fan_curve").is_some() };
+    if app.get("application_path").and_then(|x| x.as_str()).is_none() {
+        linter.report(Level::Error, "application_path", "the application path is missing".to_string());
+    }
+    if let Some(c) = app.get("fan_curve") {
+        linter.fan_curve(c);
+    }
+    match app.get("start_state") {
+        Some(x) => linter.state("start_state", x, true),
+        None => linter.report(Level::Error, "start_state", "the start state is missing".to_string()),
+    }
+    if let Some(x) = app.get("preparation") {
+        linter.preparation(x);
+    }
+    if let Some(x) = app.get("envelope") {
+        linter.envelope(x);
+    }
+    if let Some(x) = app.get("retry") {
+        linter.retry(x);
+    }
+    if let Some(x) = app.get("readback") {
+        linter.readback(x);
+    }
+    match app.get("strategy") {
+        Some(x) => linter.strategy(x),
+        None => linter.report(Level::Error, "strategy", "the strategy is missing".to_string()),
+    }
+    linter.issues
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn places(issues: &[Issue], level: Level) -> Vec<&str> {
+        issues.iter().filter(|x| x.level == level).map(|x| x.place.as_str()).collect()
+    }
+    #[test]
+    fn test_lint_example() {
+        let app: Value = serde_json::from_str(include_str!("../config-example/hpl.json")).unwrap();
+        let cluster: Value = serde_json::from_str(include_str!("../config-example/pkusc.json")).unwrap();
+        assert_eq!(lint(&app, Some(&cluster)), vec![]);
+        let app: Value = serde_json::from_str(include_str!("../config-example/hpl-ramp.json")).unwrap();
+        assert_eq!(lint(&app, Some(&cluster)), vec![]);
+    }
+    #[test]
+    fn test_lint_issues() {
+        let app: Value = serde_json::from_str(r#"
+        {
+            "application_path": "/bin/hpl",
+            "frequency_table": {"GPU_Freq": [390, 825]},
+            "start_state": {"GPU_Freq": 390, "CPU_Freq": "+100", "Fan_Speed": 40},
+            "strategy": [
+                {"hint": "T/V", "action": [{"Wait": "progress >= 90"}, {"Fan_Speed": 120}]},
+                {"hint": "T/V", "action": [{"GPU_Freq": {"to": 900}}]},
+                {"hint": "PCOL(", "action": [{"Wait": "progress < 50"}]},
+                {"phase": "end", "action": [{"Node": "node9", "GPU_Freq": 825}]}
+            ]
+        }
+        "#).unwrap();
+        let cluster: Value = serde_json::from_str(include_str!("../config-example/pkusc.json")).unwrap();
+        let issues = lint(&app, Some(&cluster));
+        assert_eq!(places(&issues, Level::Error), vec![
+            "start_state",
+            "strategy[0].action[1]",
+            "strategy[2]",
+            "strategy[3].action[0]",
+        ]);
+        assert_eq!(places(&issues, Level::Warning), vec![
+            "strategy[1]",
+            "strategy[1].action[0]",
+            "strategy[1].action[0]",
+            "strategy[2].action[0]",
+        ]);
+    }
+    #[test]
+    fn test_lint_hint_overlap() {
+        assert_eq!(required_literal(r"WR\d+"), "WR");
+        assert_eq!(required_literal(r"T\/V\s"), "T/V");
+        assert_eq!(required_literal("PCOLs?"), "PCOL");
+        assert_eq!(required_literal("WR|PCOL"), "");
+        let app: Value = serde_json::from_str(r#"
+        {
+            "application_path": "/bin/hpl",
+            "start_state": {"GPU_Freq": 390, "CPU_Freq": 2000, "Fan_Speed": 40},
+            "strategy": [
+                {"hint": "WR", "action": []},
+                {"hint": "WR\\d+", "action": []},
+                {"hint": "PCOL", "action": []},
+                {"hint": "(?i)pcol", "action": []},
+                {"hint": "Ts?", "action": []}
+            ]
+        }
+        "#).unwrap();
+        let issues = lint(&app, None);
+        assert_eq!(places(&issues, Level::Warning), vec!["strategy[1]"]);
+    }
+    #[test]
+    fn test_lint_fan_curve() {
+        let app: Value = serde_json::from_str(r#"
+        {
+            "application_path": "/bin/hpl",
+            "fan_curve": {"input": "temperature", "points": [[60, 40], [80, 120]]},
+            "start_state": {"GPU_Freq": 390, "CPU_Freq": 1000, "Fan_Speed": "curve"},
+            "strategy": [{"hint": "T/V", "action": [{"Fan_Speed": "curve"}]}]
+        }
+        "#).unwrap();
+        assert_eq!(places(&lint(&app, None), Level::Error), vec!["fan_curve.points[1]", "fan_curve"]);
+        let app: Value = serde_json::from_str(r#"
+        {
+            "application_path": "/bin/hpl",
+            "start_state": {"GPU_Freq": 390, "CPU_Freq": 1000, "Fan_Speed": "curve"},
+            "strategy": [{"hint": "T/V", "action": [{"Fan_Speed": "curve"}]}]
+        }
+        "#).unwrap();
+        assert_eq!(places(&lint(&app, None), Level::Error), vec!["start_state", "strategy[0].action[0]"]);
+    }
+    #[test]
+    fn test_lint_blocks() {
+        let app: Value = serde_json::from_str(r#"
+        {
+            "application_path": "/bin/hpl",
+            "start_state": {"GPU_Freq": 390, "CPU_Freq": 1000, "Fan_Speed": 40},
+            "preparation": ["Cool", {"Warm": "gpu_burn 60", "State": {"Fan_Speed": 120}}, {"Hold": {"GPU_Freq": 825}}, "Rest"],
+            "envelope": {"ranges": {"GPU_Freq": [1410, 390], "Foo": [1, 2]}, "min_dwell_ms": "1s"},
+            "retry": {"attempts": 3, "on_failure": "reboot"},
+            "readback": {"commands": {"GPU_Freq": "nvidia-smi"}, "tolerance": "five", "verify": "yes"},
+            "strategy": [{"hint": "T/V", "action": [{"GPU_Freq": 825}]}]
+        }
+        "#).unwrap();
+        assert_eq!(places(&lint(&app, None), Level::Error), vec![
+            "preparation[1]",
+            "preparation[3]",
+            "envelope",
+            "envelope",
+            "envelope",
+            "retry",
+            "readback",
+            "readback",
+        ]);
+    }
+}
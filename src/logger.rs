@@ -6,23 +6,33 @@ use std::fs::File;
 use std::time::Duration;
 use crate::execute::PROGRESS;
 pub static mut POWER :usize = 0;
+/// the idle power of the cluster in W, 0 when it is not calibrated
+pub static mut BASELINE: usize = 0;
 pub static mut STOP: bool = false;
 const THRESHOLD: usize = 1450;
 
 pub struct PowerLogger {
     cluster: Arc<Cluster>,
+    /// the enabled nodes with their index in the cluster file, logged one by one after the total
+    nodes: Vec<(usize, String)>,
 }
 
 impl PowerLogger {
-    pub fn new(cluster: Arc<Cluster>)-> PowerLogger {
-        PowerLogger { cluster }
+    pub fn new(cluster: Arc<Cluster>, nodes: Vec<(usize, String)>)-> PowerLogger {
+        PowerLogger { cluster, nodes }
     }
     fn get_power(&self) -> usize{
         self.cluster.collect_power_data(0).total_power
     }
+    /// `node1=700 node2=600`
+    fn node_powers(&self) -> String {
+        self.nodes.iter()
+            .map(|(i, name)| format!(" {}={}", name, self.cluster.collect_power_data(*i).total_power))
+            .collect()
+    }
     pub fn run_deamon(&self, parent_id: u32, output_file: String) {
         info!("the parent_id is {parent_id}");
-        let mut SAMPLE_FREQ = 10000;
+        let mut sample_freq = 10000;
         let mut f = File::create(output_file).unwrap();
         loop {
             unsafe {
@@ -33,32 +43,34 @@ impl PowerLogger {
             
             let power = self.get_power();
             info!("get the power of {power}");
-            unsafe {
-                
-                if PROGRESS > 0.0 {
-                    
-                    SAMPLE_FREQ = 0;
-                    f.write(format!("{PROGRESS}% {power}\n").as_bytes()).unwrap();
+            let (progress, baseline) = unsafe { (PROGRESS, BASELINE) };
+            if progress > 0.0 {
+                sample_freq = 0;
+                let nodes = self.node_powers();
+                if baseline > 0 {
+                    f.write_all(format!("{progress}% {power} {}{nodes}\n", power.saturating_sub(baseline)).as_bytes()).unwrap();
+                } else {
+                    f.write_all(format!("{progress}% {power}{nodes}\n").as_bytes()).unwrap();
+                }
+                unsafe {
                     POWER = power;
                 }
-                
             }
             #[allow(deprecated)]
             if power > THRESHOLD {
-                unsafe {
-                    warn!("get a power warning!");
-                    warn!("the process PROGRESS is {:.2}%", crate::execute::PROGRESS);
-                    warn!("the power POWER is {}W", POWER);
-                }
+                let (progress, last) = unsafe { (crate::execute::PROGRESS, POWER) };
+                warn!("get a power warning!");
+                warn!("the process PROGRESS is {:.2}%", progress);
+                warn!("the power POWER is {}W", last);
             }
             
 
-            std::thread::sleep(Duration::from_millis(SAMPLE_FREQ));
+            std::thread::sleep(Duration::from_millis(sample_freq));
         }
     }
-    pub fn start_deamon(cluster: Arc<Cluster>, output_file: &str, parent_id: u32){
+    pub fn start_deamon(cluster: Arc<Cluster>, nodes: Vec<(usize, String)>, output_file: &str, parent_id: u32){
         info!("run the power_logger");
-        let power_logger = PowerLogger::new(cluster);
+        let power_logger = PowerLogger::new(cluster, nodes);
         let file_name = output_file.to_string();
         std::thread::spawn(move|| {
             power_logger.run_deamon(parent_id, file_name);
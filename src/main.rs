@@ -1,16 +1,29 @@
-use std::{path::Path, 
-    fs::File, 
-    io::BufReader, 
-    time::Duration, 
-    thread, 
+use std::{path::{Path, PathBuf}, 
+    fs::{self, File}, 
+    time::{Duration, Instant}, 
     process
 };
 
-use app_launcher::{StateManager, State, Preparer, Executor, PowerLogger};
-use clap::Parser;
-use log::{info,warn, LevelFilter};
+use app_launcher::{StateManager, State, Preparer, PrepStep, Executor, PowerLogger};
+use app_launcher::timeline::{Clock, Timeline};
+use app_launcher::state::Knob;
+use app_launcher::replay::replay;
+use app_launcher::capture::Capture;
+use app_launcher::channel::{Message, Reporter};
+use app_launcher::config;
+use app_launcher::lint::{self, Level};
+use app_launcher::stability::{Evidence, OnTimeout, Stability};
+use app_launcher::report::Report;
+use app_launcher::baseline::{self, Baseline};
+use app_launcher::readback::ReadBack;
+use app_launcher::health::Retry;
+use app_launcher::envelope::Envelope;
+use app_launcher::fan::FanCurve;
+use app_launcher::selftest::{self, SelfTest};
+use clap::{Parser, Subcommand, ValueEnum};
+use log::{error, info, warn, LevelFilter};
 use power_controller::{Cluster, pwrctl::Command};
-use serde_json::Value;
+use serde_json::{json, Value};
 use simplelog::*;
 use std::sync::Arc;
 
@@ -28,6 +41,15 @@ struct Args {
     /// the file for power logger
     #[clap(long = "plog",value_parser, default_value = "./power.log")]
     power_logger_file: String,
+    /// the file for the report of the run
+    #[clap(long = "report", value_parser, default_value = "./report.json")]
+    report_file: String,
+    /// the file for the timestamped output of the application, it can be replayed
+    #[clap(long = "capture", value_parser, default_value = "./app_output.log")]
+    capture_file: String,
+    /// run the application under a pseudo-terminal to get its output line by line without buffering
+    #[clap(long = "pty", value_parser, default_value = "false")]
+    use_pty: bool,
     /// only do preparation
     #[clap(short = 'p', long, value_parser, default_value = "false")]
     only_prepare: bool,
@@ -46,9 +68,51 @@ struct Args {
     /// only reset every thing
     #[clap(long = "reset", value_parser, default_value = "false")]
     only_reset: bool,
+    /// check that every knob used by the strategy responds before running the application
+    #[clap(long = "selftest", value_parser, default_value = "false")]
+    selftest: bool,
     /// skip logger for debugging
     #[clap(long = "skip-log", value_parser, default_value = "false")]
     skip_logger: bool,
+    /// set a variable of the application file, e.g. `--set gpu_peak=825`, can be repeated
+    #[clap(long = "set", value_parser = config::parse_override, global = true)]
+    overrides: Vec<(String, Value)>,
+    #[clap(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// replay a recorded application output against a strategy without touching the hardware
+    Replay {
+        /// the recorded output, lines may start with a `[seconds]` timestamp
+        #[clap(long, value_parser)]
+        log: String,
+        /// the application file with the strategy to check
+        #[clap(long, value_parser)]
+        app: String,
+        /// dry-run jumps over every delay, simulated follows the recorded timing
+        #[clap(long, value_enum, default_value = "dry-run")]
+        backend: Backend,
+    },
+    /// print the application file with its extends, includes, presets and variables resolved
+    Resolve,
+    /// check an application file before running it, against the servers of the cluster file
+    Lint {
+        #[clap(value_parser)]
+        file: String,
+    },
+    /// send a message to the launcher running this script: PHASE name, PROGRESS 42.5 or HINT text
+    Notify {
+        #[clap(value_parser, required = true)]
+        message: Vec<String>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum Backend {
+    DryRun,
+    Simulated,
 }
 
 
@@ -57,7 +121,7 @@ fn print_args_for_debug(a: &Args) {
     info!("application file is: {}, does it exist? {}", &a.application_file, Path::new(&a.application_file).exists());
     info!("cluster file is: {}, does it exist? {}", &a.application_file, Path::new(&a.cluster_file).exists());
     info!("Will the logger start? {}", a.skip_logger);
-    let app_info = extract_application(a.application_file.as_str());
+    let app_info = extract_application(a.application_file.as_str(), &a.overrides);
 
     info!("the application to launch is {:?}", app_info["application_path"]);
     info!("the start state is {:?}", app_info["start_state"]);
@@ -66,47 +130,101 @@ fn print_args_for_debug(a: &Args) {
     
 
 }
-fn extract_application(file_name: &str) -> Value {
-    let file : File = File::open(file_name).unwrap();
-    let reader = BufReader::new(file);
-    serde_json::from_reader(reader).unwrap()
+fn extract_application(file_name: &str, overrides: &[(String, Value)]) -> Value {
+    match config::load(Path::new(file_name)).and_then(|x| config::resolve(x, overrides)) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}: {}", file_name, e);
+            process::exit(1);
+        }
+    }
+}
+/// the cluster described by a JSON, TOML or YAML file
+fn load_cluster(file_name: &str) -> Cluster {
+    match config::with_cluster_json(Path::new(file_name), Cluster::from_file) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+/// the names of the enabled servers in the cluster file
+/// the enabled servers of the cluster file with their index in it
+fn servers(file_name: &str) -> Vec<(usize, String)> {
+    let cluster_info = config::read(Path::new(file_name)).unwrap();
+    cluster_info["servers"].as_array()
+        .map(|servers| servers.iter().enumerate()
+            .filter(|(_, s)| s["enabled"].as_bool().unwrap_or(true))
+            .filter_map(|(i, s)| s["name"].as_str().map(|n| (i, n.to_string())))
+            .collect())
+        .unwrap_or_default()
+}
+fn node_names(file_name: &str) -> Vec<String> {
+    servers(file_name).into_iter().map(|(_, n)| n).collect()
 }
-fn do_preparation(p: &Preparer) {
+fn do_preparation(p: &mut Preparer, steps: &[PrepStep]) -> Option<Evidence> {
     info!("preparedness begins");
-    p.fiercely_blowing();
-    info!("blowing ends");
-    p.wait_for_stability();
-    info!("power is stable");
+    let evidence = p.run(steps);
+    if let Some(e) = &evidence {
+        info!("power is {}", e);
+    }
     info!("preparedness ends");
+    evidence
+}
+
+/// the directory of the report, where the other files of the run go
+fn run_dir(report_file: &str) -> PathBuf {
+    match Path::new(report_file).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// the timeline goes into the report, and as text next to it
+fn save_report(report: &mut Report, timeline: &Timeline, file_name: &str) {
+    report.set("timeline", timeline.to_value());
+    let dir = run_dir(file_name);
+    if let Err(e) = timeline.save(&dir) {
+        warn!("can not write the timeline in {}: {}", dir.display(), e);
+    }
+    match report.save(Path::new(file_name)) {
+        Ok(_) => info!("the report is written in {}", file_name),
+        Err(e) => warn!("can not write the report {}: {}", file_name, e),
+    }
 }
 
 fn do_executation(e: &mut Executor) {
     e.run();
 }
 
-fn read_progress_and_power() {
-    unsafe {
-        warn!("get a power warning!");
-        warn!("the process PROGRESS is {:.2}%", app_launcher::execute::PROGRESS);
-        warn!("the power POWER is {}W", app_launcher::logger::POWER);
+/// the retry policy of the application file, the default one when the file can not be read
+fn configured_retry(args: &Args) -> Retry {
+    match config::load(Path::new(&args.application_file)).and_then(|x| config::resolve(x, &args.overrides)) {
+        Ok(app_info) => app_info.get("retry").map(Retry::from).unwrap_or_default(),
+        Err(e) => {
+            warn!("can not read the retry policy in {}: {}, the default one is used", args.application_file, e);
+            Retry::default()
+        }
     }
 }
 
 fn reset_everything(args: &Args) {
-    let cluster = Cluster::from_file(Path::new(&args.cluster_file));
-    let s = vec![
-        String::from("RESET FAN"), 
-        String::from("RESET GPU"), 
-        String::from("RESET CPU")
-    ];
+    let cluster = load_cluster(&args.cluster_file);
+    let retry = configured_retry(args);
+    // the knobs added later are only reset where the power controller has a command for them
+    let s = Knob::reset_commands();
     s.iter().for_each(|c| {
         let command = Command::parse(&cluster, c);
         match command {
             Ok(x) => {
-                cluster.run_command(&x);
+                let (result, attempts) = retry.run(|| cluster.run_command(&x).map_err(|e| e.to_string()));
+                if let Err(e) = result {
+                    error!("{} fails after {} attempts: {}", c, attempts, e);
+                }
             },
             Err(e) => {
-                println!("{}", e);
+                warn!("can not reset with {}: {}", c, e);
             }
         };
 
@@ -114,9 +232,64 @@ fn reset_everything(args: &Args) {
     info!("everything is reset");
 }
 
-fn main_process(args: &Args) {
-    
-    
+fn do_replay(log: &str, application_file: &str, cluster_file: &str, backend: &Backend, overrides: &[(String, Value)]) {
+    let app_info = extract_application(application_file, overrides);
+    let clock = match backend {
+        Backend::DryRun => Clock::Virtual(Duration::ZERO),
+        Backend::Simulated => Clock::Real(Instant::now()),
+    };
+    let mut state_manager = StateManager::dry_run(State::from(&app_info["start_state"]), clock);
+    if let Some(t) = app_info.get("frequency_table") {
+        state_manager.load_frequency_table(t);
+    }
+    // the cluster file is only needed for the names of the servers
+    if Path::new(cluster_file).exists() {
+        state_manager.set_nodes(node_names(cluster_file));
+    }
+    let mut executor = Executor::replay(&app_info["strategy"], &mut state_manager);
+    replay(&mut executor, File::open(log).unwrap());
+    print!("{}", executor.state_manager().timeline());
+}
+
+fn do_notify(message: &[String]) {
+    let s = message.join(" ");
+    let m = match Message::parse(&s) {
+        Some(m) => m,
+        None => {
+            eprintln!("unknown message: {}", s);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = Reporter::from_env().send(&m) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn do_lint(file_name: &str, cluster_file: &str, overrides: &[(String, Value)]) {
+    let cluster_info = if Path::new(cluster_file).exists() {
+        config::read(Path::new(cluster_file)).ok()
+    } else {
+        None
+    };
+    let issues = match config::load(Path::new(file_name)).and_then(|x| config::resolve(x, overrides)) {
+        Ok(app_info) => lint::lint(&app_info, cluster_info.as_ref()),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    };
+    for i in &issues {
+        println!("{}", i);
+    }
+    let errors = issues.iter().filter(|i| i.level == Level::Error).count();
+    println!("{}: {} error(s), {} warning(s)", file_name, errors, issues.len() - errors);
+    if errors > 0 {
+        process::exit(1);
+    }
+}
+
+fn init_logger(args: &Args) {
     if args.debug_level {
         CombinedLogger::init(
             vec![
@@ -126,28 +299,97 @@ fn main_process(args: &Args) {
         ).unwrap();
         
     }
+}
+
+fn main_process(args: &Args) {
     if args.only_reset {
         return;
     }
     if args.setting_check {
-        print_args_for_debug(&args);
+        print_args_for_debug(args);
         return
     }
-    let cluster = Arc::new(Cluster::from_file(Path::new(&args.cluster_file)));
-    let app_info = extract_application(args.application_file.as_str());
+    let cluster = Arc::new(load_cluster(&args.cluster_file));
+    let app_info = extract_application(args.application_file.as_str(), &args.overrides);
 
     let mut state_manager = StateManager::new(&cluster, State::from(&app_info["start_state"]));
+    if let Err(e) = state_manager.check_state(state_manager.current_state()) {
+        panic!("invalid start state: {}", e);
+    }
+    if let Some(t) = app_info.get("frequency_table") {
+        state_manager.load_frequency_table(t);
+    }
+    state_manager.set_nodes(node_names(&args.cluster_file));
+    if let Some(r) = app_info.get("readback") {
+        state_manager.set_readback(ReadBack::from(r));
+    }
+    if let Some(r) = app_info.get("retry") {
+        state_manager.set_retry(Retry::from(r));
+    }
+    if let Some(e) = app_info.get("envelope") {
+        state_manager.set_envelope(Envelope::from(e));
+    }
+    if let Some(c) = app_info.get("fan_curve") {
+        state_manager.set_fan_curve(FanCurve::from(c));
+    }
     
-    let preparer = Preparer::new(&cluster, &state_manager, Some(Duration::from_millis(args.blowing_time)));
+    let mut report = Report::new();
+    report.set("application", Value::from(args.application_file.as_str()));
+    let stability = app_info.get("stability").map(Stability::from).unwrap_or_default();
+    let steps = match app_info.get("preparation") {
+        Some(x) => x.as_array().expect("preparation must be an array of steps").iter().map(PrepStep::from).collect(),
+        None => PrepStep::default_sequence(),
+    };
 
-    if !args.skip_prepare {
-        do_preparation(&preparer);
+    // the knobs are checked at the start state, before the preparation moves them
+    if args.selftest {
+        let selftest = app_info.get("selftest").map(SelfTest::from).unwrap_or_default();
+        let knobs = selftest::knobs_used(&app_info["strategy"]);
+        let checks = selftest.run(&mut state_manager, &|| cluster.collect_power_data(0).total_power, &knobs);
+        report.set("selftest", Value::Array(checks.iter().map(|c| c.to_value()).collect()));
+        for c in &checks {
+            info!("[selftest]{}", c);
+        }
+        if let Some(c) = checks.iter().find(|c| !c.ok) {
+            error!("[selftest]{} does not respond, the run is aborted", c.knob.key());
+            state_manager.reset();
+            save_report(&mut report, state_manager.timeline(), &args.report_file);
+            return;
+        }
+    }
+
+    let run_dir = run_dir(&args.report_file);
+    let idle = if !args.skip_prepare {
+        let mut preparer = Preparer::new(&mut state_manager, Some(Duration::from_millis(args.blowing_time)));
+        preparer.set_stability(stability.clone());
+        if let Some(evidence) = do_preparation(&mut preparer, &steps) {
+            report.set("stability", evidence.to_value());
+            if !evidence.stable && stability.on_timeout == OnTimeout::Abort {
+                error!("the power is not stable, the run is aborted");
+                save_report(&mut report, state_manager.timeline(), &args.report_file);
+                return;
+            }
+        }
+        let b = preparer.calibrate(&servers(&args.cluster_file));
+        if let Err(e) = b.save(&run_dir) {
+            warn!("can not write the baseline in {}: {}", run_dir.display(), e);
+        }
+        Some(b)
     }
     else {
         state_manager.reset();
+        // without preparation the baseline of an earlier run in the same directory is used
+        Baseline::load(&run_dir)
+    };
+    if let Some(b) = &idle {
+        report.set("baseline", b.to_value());
+        unsafe {
+            app_launcher::logger::BASELINE = b.idle_w.round() as usize;
+        }
     }
 
     if args.only_prepare {
+        save_report(&mut report, state_manager.timeline(), &args.report_file);
         return;
     }
 
@@ -155,16 +397,48 @@ fn main_process(args: &Args) {
     
     
     if !args.skip_logger {
-        PowerLogger::start_deamon(Arc::clone(&cluster),
+        PowerLogger::start_deamon(Arc::clone(&cluster), servers(&args.cluster_file),
          args.power_logger_file.as_str(), process::id());
     }
     let mut executor = Executor::new(application_path, 
-            &app_info["strategy"], &cluster, &mut state_manager);
+            &app_info["strategy"], &mut state_manager);
+    executor.set_capture(Capture::create(&args.capture_file));
+    executor.set_pty(args.use_pty);
 
     do_executation(&mut executor);
+    if let Some(r) = executor.state_manager().refused() {
+        report.set("refused", Value::from(r));
+    }
+    report.set("health", executor.state_manager().health());
+    let envelope = executor.state_manager().envelope();
+    report.set("envelope", json!({"clamped": envelope.clamped, "deferred": envelope.deferred, "dropped": envelope.dropped}));
+    if !args.skip_logger {
+        let log = fs::read_to_string(&args.power_logger_file).unwrap_or_default();
+        report.set("power", baseline::power_summary(&log, idle.as_ref()));
+    }
+    save_report(&mut report, executor.state_manager().timeline(), &args.report_file);
 }
 fn main() {
     let args = Args::parse();
+    // a notification comes from a script of the application, it must not touch the log of the launcher
+    if let Some(Commands::Notify { message }) = &args.command {
+        do_notify(message);
+        return;
+    }
+    if let Some(Commands::Resolve) = &args.command {
+        let app_info = extract_application(&args.application_file, &args.overrides);
+        println!("{}", serde_json::to_string_pretty(&app_info).unwrap());
+        return;
+    }
+    if let Some(Commands::Lint { file }) = &args.command {
+        do_lint(file, &args.cluster_file, &args.overrides);
+        return;
+    }
+    init_logger(&args);
+    if let Some(Commands::Replay { log, app, backend }) = &args.command {
+        do_replay(log, app, &args.cluster_file, backend, &args.overrides);
+        return;
+    }
     main_process(&args);
     reset_everything(&args);
 }
@@ -1,32 +1,131 @@
-use crate::StateManager;
-use power_controller::Cluster;
+use crate::{State, StateManager};
+use serde_json::Value;
+use crate::stability::{Criterion, Evidence, OnTimeout, Stability};
+use crate::baseline::{Baseline, NodeIdle};
+use crate::readback::numbers;
+use crate::shell;
 use std::{
-    time::Duration,
+    process,
+    time::{Duration, Instant},
     thread::sleep
 };
 use log::*;
 const DEFAULT_BLOWING_TIME: Duration = Duration::from_millis(30);
-const WINDOW_SIZE: usize = 10;
-const THRESHOLD: usize = 30;
 
-pub struct Preparer<'a> {
-    state_manager: &'a StateManager<'a>,
-    cluster: &'a Cluster,
-    blowing_time: Option<Duration>
+/// one step of the preparation, the application file lists them in `"preparation"`:
+/// `[{"Cool": 10000}, {"Warm": "gpu_burn 600", "State": {"GPU_Freq": 1200}}, {"Hold": {"Fan_Speed": 60, "Time": 5000}}, "WaitStable"]`
+#[derive(Clone, PartialEq, Debug)]
+pub enum PrepStep {
+    /// fans at 100% for the time in milisecond (`"Cool"` alone uses the blowing time), then the start state
+    Cool(Option<Duration>),
+    /// switch to the state and run the command until the power is stable, then kill it
+    Warm {
+        command: String,
+        state: Option<State>,
+    },
+    /// switch to the state and stay there for its `Time`
+    Hold(State),
+    WaitStable,
 }
 
-impl<'a> Preparer<'a> {
-    pub fn new(cluster: &'a Cluster, state_manager: &'a StateManager<'a>, blowing_time: Option<Duration>) -> Preparer<'a>{
+impl From<&Value> for PrepStep {
+    fn from(v: &Value) -> Self {
+        match v.as_str() {
+            Some("Cool") => return PrepStep::Cool(None),
+            Some("WaitStable") => return PrepStep::WaitStable,
+            Some(x) => panic!("unknown preparation step {}, use Cool, Warm, Hold or WaitStable", x),
+            None => {},
+        }
+        let obj = v.as_object().expect("a preparation step must be a string or an object");
+        if let Some(x) = obj.get("Cool") {
+            return PrepStep::Cool(x.as_u64().map(Duration::from_millis));
+        }
+        if let Some(x) = obj.get("Warm") {
+            return PrepStep::Warm {
+                command: x.as_str().expect("Warm must be a shell command").to_string(),
+                state: obj.get("State").map(State::from),
+            };
+        }
+        if let Some(x) = obj.get("Hold") {
+            return PrepStep::Hold(State::from(x));
+        }
+        if obj.contains_key("WaitStable") {
+            return PrepStep::WaitStable;
+        }
+        panic!("unknown preparation step {}, use Cool, Warm, Hold or WaitStable", v);
+    }
+}
+
+impl PrepStep {
+    /// what the preparation does without `"preparation"`
+    pub fn default_sequence() -> Vec<PrepStep> {
+        vec![PrepStep::Cool(None), PrepStep::WaitStable]
+    }
+}
+
+pub struct Preparer<'a, 'b> {
+    state_manager: &'b mut StateManager<'a>,
+    blowing_time: Option<Duration>,
+    stability: Stability,
+}
+
+impl<'a, 'b> Preparer<'a, 'b> {
+    pub fn new(state_manager: &'b mut StateManager<'a>, blowing_time: Option<Duration>) -> Preparer<'a, 'b>{
         Preparer { 
             state_manager, 
-            cluster, 
-            blowing_time 
+            blowing_time,
+            stability: Stability::default(),
+        }
+    }
+    pub fn set_stability(&mut self, stability: Stability) {
+        let uses_temperature = stability.criteria.iter().any(|c| matches!(c, Criterion::Temperature(_)));
+        if uses_temperature && stability.temperature_command.is_none() {
+            warn!("the temperature criterion is left out without a temperature_command");
+        }
+        self.stability = stability;
+    }
+    /// run the steps in order, the result is the evidence of the last wait for stability
+    /// a wait which times out with `"on_timeout": "abort"` ends the preparation at once
+    /// the cluster is back at the start state afterwards, whatever the steps switched to
+    pub fn run(&mut self, steps: &[PrepStep]) -> Option<Evidence> {
+        let mut evidence = None;
+        for step in steps {
+            info!("[preparation]{:?}", step);
+            match step {
+                PrepStep::Cool(time) => self.cool(*time),
+                PrepStep::Warm { command, state } => evidence = Some(self.warm(command, state)),
+                PrepStep::Hold(state) => self.state_manager.switch_state(state.clone()),
+                PrepStep::WaitStable => evidence = Some(self.wait_for_stability()),
+            }
+            if let Some(e) = &evidence {
+                if !e.stable && self.stability.on_timeout == OnTimeout::Abort {
+                    break;
+                }
+            }
+        }
+        self.state_manager.restore_start();
+        evidence
+    }
+    /// bring the machine to its operating temperature with a load, until the power is stable
+    fn warm(&mut self, command: &str, state: &Option<State>) -> Evidence {
+        let mut child = match shell::spawn(command) {
+            Ok(x) => x,
+            Err(e) => panic!("can not run the warm-up command {}: {}", command, e),
+        };
+        if let Some(s) = state {
+            self.state_manager.switch_state(s.clone());
         }
+        let evidence = self.wait_for_stability();
+        info!("[preparation]the warm-up is {}", evidence);
+        shell::kill(&mut child);
+        evidence
+    }
+    pub fn fiercely_blowing(&mut self) {
+        self.cool(None);
     }
-    pub fn fiercely_blowing(&self) {
-        
+    fn cool(&mut self, time: Option<Duration>) {
         self.state_manager.set_fan_speed(100);
-        sleep(match self.blowing_time {
+        sleep(match time.or(self.blowing_time) {
             Some(x) => {
                 x
             },
@@ -34,53 +133,121 @@ impl<'a> Preparer<'a> {
                 DEFAULT_BLOWING_TIME
             }
         });
-        self.state_manager.reset();
+        self.state_manager.restore_start();
+    }
+    /// the highest number printed by the temperature command
+    fn read_temperature(&self) -> Option<f64> {
+        let command = self.stability.temperature_command.as_ref()?;
+        let output = match process::Command::new("sh").arg("-c").arg(command).output() {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("[waiting stability]can not read the temperature: {}", e);
+                return None;
+            }
+        };
+        numbers(&String::from_utf8_lossy(&output.stdout)).into_iter().reduce(f64::max)
+    }
+    /// the idle power of the cluster and of each node at the start state, read over one window once the power is stable
+    /// `nodes` are the enabled nodes with their index in the cluster file
+    pub fn calibrate(&self, nodes: &[(usize, String)]) -> Baseline {
+        let mut window = self.stability.window();
+        let mut node_windows: Vec<_> = nodes.iter().map(|_| self.stability.window()).collect();
+        let mut readings = 0;
+        while !window.is_full() {
+            if readings > 0 {
+                sleep(self.stability.interval);
+            }
+            window.push(self.state_manager.power() as f64);
+            for ((i, _), w) in nodes.iter().zip(&mut node_windows) {
+                w.push(self.state_manager.node_power(*i) as f64);
+            }
+            readings += 1;
+        }
+        let nodes: Vec<NodeIdle> = nodes.iter().zip(&node_windows)
+            .map(|((_, name), w)| NodeIdle { name: name.clone(), idle_w: w.mean(), stddev_w: w.stddev() })
+            .collect();
+        let baseline = Baseline { idle_w: window.mean(), stddev_w: window.stddev(), readings, nodes };
+        info!("[calibration]the idle power is {:.1}W (stddev {:.1}W)", baseline.idle_w, baseline.stddev_w);
+        for n in &baseline.nodes {
+            info!("[calibration]the idle power of {} is {:.1}W (stddev {:.1}W)", n.name, n.idle_w, n.stddev_w);
+        }
+        baseline
     }
-    pub fn wait_for_stability(&self) {
-        let mut rec = vec![self.cluster.collect_power_data(0).total_power];
-        let (mut min_index, mut max_index)  = (0, 0);
+    /// read the power until it stays in the tolerance for a whole window, or until the timeout
+    pub fn wait_for_stability(&self) -> Evidence {
+        let stability = &self.stability;
+        let start = Instant::now();
+        let mut window = stability.window();
+        let mut readings = 0;
         loop {
-            let x = self.cluster.collect_power_data(0).total_power;
+            let x = self.state_manager.power();
+            readings += 1;
             info!("[waiting stability]the newly read power is {}", x);
-            if x > rec[max_index] {
-                max_index = rec.len();
-            }
-            if x < rec[min_index] {
-                min_index = rec.len();
+            window.push(x as f64);
+            if let Some(t) = self.read_temperature() {
+                window.push_temperature(t);
             }
-    
-            rec.push(x);
-    
-            if rec.len() > WINDOW_SIZE {
-                let st = rec.len() - WINDOW_SIZE;
-                let ed = rec.len();
-                if max_index < st {
-                    max_index = st;
-    
-                    for i in (st + 1)..ed {
-                        if rec[i] > rec[max_index] {
-                            max_index = i;
-                        }
-                    }
-                }
-                if min_index < st {
-                    min_index = st;
-                    for i in (st + 1)..ed {
-                        if rec[i] < rec[min_index] {
-                            min_index = i;
-                        }
-                    }
+            if window.is_full() {
+                warn!("the highest power is {}, the lowest is {}, the difference is {}, the slope is {:.1}W/min",
+                    window.max(), window.min(), window.max() - window.min(), window.slope());
+                if stability.holds(&window) {
+                    info!("the power is stable: {:?} of {:?}", stability.combine, stability.criteria);
+                    return window.evidence(true, start.elapsed(), readings);
                 }
-                warn!("the highest power is {}, the lowest is {}, the difference is {}", 
-                    rec[max_index], rec[min_index], rec[max_index] - rec[min_index]);
-                if rec[max_index] - rec[min_index] <= THRESHOLD {
-                    break;
+            }
+            if let Some(t) = stability.timeout {
+                if start.elapsed() >= t {
+                    warn!("the power is not stable in {:?}", t);
+                    return window.evidence(false, start.elapsed(), readings);
                 }
             }
-            
-        };
-        info!("the power variation is stable in the threshold {}", THRESHOLD);
-    } 
+            sleep(stability.interval);
+        }
+    }
 }
 
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::timeline::Clock;
+    #[test]
+    fn test_prep_steps_from_value() {
+        let v: Value = serde_json::from_str(r#"
+        [
+            {"Cool": 5000},
+            {"Warm": "gpu_burn 600", "State": {"GPU_Freq": 1200}},
+            {"Hold": {"Fan_Speed": 60, "Time": 100}},
+            "WaitStable"
+        ]
+        "#).unwrap();
+        let steps: Vec<PrepStep> = v.as_array().unwrap().iter().map(PrepStep::from).collect();
+        assert_eq!(steps[0], PrepStep::Cool(Some(Duration::from_millis(5000))));
+        assert_eq!(steps[1], PrepStep::Warm {
+            command: "gpu_burn 600".to_string(),
+            state: Some(State::new(None, Some(1200), None, None)),
+        });
+        assert_eq!(steps[2], PrepStep::Hold(State::new(None, None, Some(60), Some(Duration::from_millis(100)))));
+        assert_eq!(steps[3], PrepStep::WaitStable);
+        assert_eq!(PrepStep::from(&Value::from("Cool")), PrepStep::default_sequence()[0]);
+    }
+    #[test]
+    fn test_warm_then_cool() {
+        let start = State::new(Some(1000), Some(390), Some(40), None);
+        let mut state_manager = StateManager::dry_run(start.clone(), Clock::Real(Instant::now()));
+        let mut preparer = Preparer::new(&mut state_manager, Some(Duration::ZERO));
+        preparer.set_stability(Stability {
+            window: Duration::from_millis(200),
+            interval: Duration::from_millis(100),
+            ..Stability::default()
+        });
+        let warm = State::new(Some(2000), Some(825), Some(60), None);
+        preparer.run(&[PrepStep::Warm { command: "sleep 30".to_string(), state: Some(warm.clone()) }, PrepStep::Cool(None)]);
+        assert_eq!(state_manager.current_state(), &start);
+        // the warm-up state is not kept after the preparation either
+        let mut preparer = Preparer::new(&mut state_manager, Some(Duration::ZERO));
+        preparer.run(&[PrepStep::Hold(warm)]);
+        assert_eq!(state_manager.current_state(), &start);
+    }
+}
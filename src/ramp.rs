@@ -0,0 +1,127 @@
+use crate::state::Knob;
+use serde_json::Value;
+use std::time::Duration;
+
+/// how fast the value moves along a ramp
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Curve {
+    Linear,
+    /// big steps first, small steps when getting close to the target
+    Exp,
+}
+
+/// steepness of the exponential curve
+const EXP_RATE: f64 = 3.0;
+
+/// a gradual change of one knob, written as
+/// `"GPU_Freq": {"to": 825, "over_ms": 2000, "step": 15, "curve": "linear"}`
+#[derive(Clone, PartialEq, Debug)]
+pub struct Ramp {
+    pub knob: Knob,
+    pub to: usize,
+    pub over: Duration,
+    /// the largest change of one step, without it every supported value on the way is a step
+    pub step: Option<usize>,
+    pub curve: Curve,
+}
+
+impl Ramp {
+    pub fn from_value(knob: Knob, v: &Value) -> Ramp {
+        let obj = v.as_object().expect("a ramp must be an object");
+        Ramp {
+            knob,
+            to: obj.get("to").and_then(|x| x.as_u64()).expect("a ramp needs a number \"to\"") as usize,
+            over: Duration::from_millis(obj.get("over_ms").map_or(0, |x| x.as_u64().expect("over_ms must be a number of milisecond"))),
+            step: obj.get("step").map(|x| {
+                let step = x.as_u64().expect("step must be a number") as usize;
+                assert!(step > 0, "the step of a ramp can not be 0");
+                step
+            }),
+            curve: match obj.get("curve").map(|x| x.as_str().expect("curve must be a string")) {
+                None | Some("linear") => Curve::Linear,
+                Some("exp") => Curve::Exp,
+                Some(x) => panic!("unknown curve {}, use linear or exp", x),
+            },
+        }
+    }
+    fn fraction(&self, t: f64) -> f64 {
+        match self.curve {
+            Curve::Linear => t,
+            Curve::Exp => (1.0 - (-EXP_RATE * t).exp()) / (1.0 - (-EXP_RATE).exp()),
+        }
+    }
+    /// the values to set and when to set them, counted from the start of the ramp
+    /// the first step is at once and the last one (always `to`) at `over`,
+    /// values are moved to the nearest entry of `table` when there is one
+    pub fn steps(&self, from: usize, table: Option<&[usize]>) -> Vec<(Duration, usize)> {
+        let (lo, hi) = (from.min(self.to), from.max(self.to));
+        let supported: Vec<usize> = table
+            .map(|t| t.iter().copied().filter(|x| *x > lo && *x < hi).collect())
+            .unwrap_or_default();
+        let n = match self.step {
+            Some(step) => (hi - lo).div_ceil(step),
+            None if table.is_some() => supported.len() + 1,
+            None => 1,
+        }
+        .max(1);
+        let mut values = vec![];
+        for i in 1..=n {
+            let f = self.fraction(i as f64 / n as f64);
+            let mut v = (from as f64 + (self.to as f64 - from as f64) * f).round() as usize;
+            if i == n {
+                v = self.to;
+            } else if let Some(t) = table {
+                v = nearest(t, v).unwrap_or(v);
+            }
+            if values.last() != Some(&v) && v != from {
+                values.push(v);
+            }
+        }
+        let m = values.len();
+        values
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| {
+                let at = if m > 1 { self.over * i as u32 / (m - 1) as u32 } else { Duration::ZERO };
+                (at, v)
+            })
+            .collect()
+    }
+}
+
+/// the entry of `table` closest to `v`
+pub fn nearest(table: &[usize], v: usize) -> Option<usize> {
+    table.iter().copied().min_by_key(|x| if *x > v { x - v } else { v - x })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn ramp(to: usize, over_ms: u64, step: Option<usize>, curve: Curve) -> Ramp {
+        Ramp { knob: Knob::GpuFreq, to, over: Duration::from_millis(over_ms), step, curve }
+    }
+    #[test]
+    fn test_from_value() {
+        let v = serde_json::from_str(r#"{"to": 825, "over_ms": 2000, "step": 15, "curve": "exp"}"#).unwrap();
+        assert_eq!(Ramp::from_value(Knob::GpuFreq, &v), ramp(825, 2000, Some(15), Curve::Exp));
+    }
+    #[test]
+    fn test_linear_steps() {
+        let steps = ramp(795, 10, Some(135), Curve::Linear).steps(390, None);
+        assert_eq!(steps, vec![
+            (Duration::from_millis(0), 525),
+            (Duration::from_millis(5), 660),
+            (Duration::from_millis(10), 795),
+        ]);
+    }
+    #[test]
+    fn test_steps_follow_table() {
+        let table = [390, 585, 675, 795, 825];
+        let steps = ramp(825, 30, None, Curve::Linear).steps(585, Some(&table));
+        let values: Vec<usize> = steps.iter().map(|x| x.1).collect();
+        assert_eq!(values, vec![675, 795, 825]);
+        let steps = ramp(390, 0, Some(100), Curve::Exp).steps(825, Some(&table));
+        assert_eq!(steps.last(), Some(&(Duration::ZERO, 390)));
+        assert!(steps.iter().all(|x| table.contains(&x.1)));
+    }
+}
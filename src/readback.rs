@@ -0,0 +1,116 @@
+use crate::state::Knob;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::process;
+use std::time::Duration;
+
+const DEFAULT_TOLERANCE: f64 = 0.05;
+const DEFAULT_RETRIES: usize = 2;
+const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// every number printed in `s`, such as the clocks of each GPU
+pub fn numbers(s: &str) -> Vec<f64> {
+    s.split(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+        .filter_map(|x| x.parse::<f64>().ok())
+        .collect()
+}
+
+/// shell commands printing the value of a knob as the hardware has it, one number per device, written as
+/// `"readback": {"commands": {"GPU_Freq": "nvidia-smi --query-gpu=clocks.sm --format=csv,noheader,nounits"}, "tolerance": "5%"}`
+/// with `"verify": true` every state switch is checked, `"retries": 2, "retry_ms": 100, "fail_run": false`
+/// the commands run where the launcher runs, a value of another node is read with e.g. `ssh node1 nvidia-smi ...`
+#[derive(Clone, PartialEq, Debug)]
+pub struct ReadBack {
+    commands: HashMap<Knob, String>,
+    /// how far a read value may be from the set one, a fraction of it
+    pub tolerance: f64,
+    /// check the values after every state switch
+    pub verify: bool,
+    /// how many more times a value is read when it does not agree yet
+    pub retries: usize,
+    pub retry_interval: Duration,
+    /// stop the application when the hardware refuses a value
+    pub fail_run: bool,
+}
+
+impl Default for ReadBack {
+    fn default() -> Self {
+        ReadBack {
+            commands: HashMap::new(),
+            tolerance: DEFAULT_TOLERANCE,
+            verify: false,
+            retries: DEFAULT_RETRIES,
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+            fail_run: false,
+        }
+    }
+}
+
+impl From<&Value> for ReadBack {
+    fn from(v: &Value) -> Self {
+        let obj = v.as_object().expect("readback must be an object");
+        let mut commands = HashMap::new();
+        if let Some(c) = obj.get("commands") {
+            for (key, command) in c.as_object().expect("the commands of readback must be an object") {
+                let knob = Knob::from_key(key).unwrap_or_else(|| panic!("unknown knob {} in readback", key));
+                commands.insert(knob, command.as_str().expect("a readback command must be a string").to_string());
+            }
+        }
+        let flag = |key: &str| obj.get(key).is_some_and(|x| x.as_bool().unwrap_or_else(|| panic!("{} must be true or false", key)));
+        ReadBack {
+            commands,
+            tolerance: obj.get("tolerance").map_or(DEFAULT_TOLERANCE, |x| match x {
+                Value::String(s) => s.trim().trim_end_matches('%').trim().parse::<f64>().expect("need a tolerance like \"5%\"") / 100.0,
+                x => x.as_f64().expect("need a tolerance like \"5%\""),
+            }),
+            verify: flag("verify"),
+            retries: obj.get("retries").map_or(DEFAULT_RETRIES, |x| x.as_u64().expect("retries must be a number") as usize),
+            retry_interval: obj.get("retry_ms")
+                .map_or(DEFAULT_RETRY_INTERVAL, |x| Duration::from_millis(x.as_u64().expect("need a number of milisecond"))),
+            fail_run: flag("fail_run"),
+        }
+    }
+}
+
+impl ReadBack {
+    pub fn has(&self, knob: Knob) -> bool {
+        self.commands.contains_key(&knob)
+    }
+    /// the values printed by the command of `knob`, `None` without a command
+    pub fn read(&self, knob: Knob) -> Option<Result<Vec<f64>, String>> {
+        let command = self.commands.get(&knob)?;
+        let output = match process::Command::new("sh").arg("-c").arg(command).output() {
+            Ok(x) => x,
+            Err(e) => return Some(Err(format!("can not run {}: {}", command, e))),
+        };
+        if !output.status.success() {
+            return Some(Err(format!("{} -> {}", command, output.status)));
+        }
+        let values = numbers(&String::from_utf8_lossy(&output.stdout));
+        if values.is_empty() {
+            return Some(Err(format!("{} prints no number", command)));
+        }
+        Some(Ok(values))
+    }
+    /// whether every read value is close enough to `expected`
+    pub fn agrees(&self, expected: usize, values: &[f64]) -> bool {
+        let margin = (expected as f64 * self.tolerance).max(1.0);
+        values.iter().all(|x| (x - expected as f64).abs() <= margin)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_readback() {
+        assert_eq!(numbers("1410 MHz\n1395 MHz\n"), vec![1410.0, 1395.0]);
+        let v = serde_json::from_str(r#"{"commands": {"Fan_Speed": "echo 59; echo 61"}, "tolerance": "2%"}"#).unwrap();
+        let r = ReadBack::from(&v);
+        assert!(r.has(Knob::FanSpeed) && !r.has(Knob::GpuFreq));
+        assert_eq!(r.read(Knob::GpuFreq), None);
+        let values = r.read(Knob::FanSpeed).unwrap().unwrap();
+        assert!(r.agrees(60, &values));
+        assert!(!r.agrees(80, &values));
+    }
+}
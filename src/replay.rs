@@ -0,0 +1,66 @@
+use crate::Executor;
+use crate::capture::{self, Stream};
+use crate::channel::Message;
+use crate::lines::LineReader;
+use std::io::Read;
+use std::time::Duration;
+use log::info;
+use regex::Regex;
+use lazy_static::lazy_static;
+
+/// split the optional `[seconds]` timestamp from a recorded line
+pub fn parse_line(s: &str) -> (Option<Duration>, &str) {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^\[\s*(?P<secs>\d+(\.\d+)?)\]\s?").unwrap();
+    }
+    match RE.captures(s) {
+        Some(caps) => {
+            let secs: f64 = caps["secs"].parse().unwrap();
+            (Some(Duration::from_secs_f64(secs)), &s[caps.get(0).unwrap().end()..])
+        },
+        None => (None, s)
+    }
+}
+
+/// feed a recorded application output through the executor
+/// the log is either plain output or a capture file written by the launcher,
+/// the clock of the state manager follows the timestamps of the lines
+pub fn replay<R: Read>(executor: &mut Executor, reader: R) {
+    info!("[replay]begins");
+    let mut reader = LineReader::new(reader);
+    while let Some(line) = reader.next_line().expect("can not read the recorded log") {
+        let (at, text, stream) = match capture::parse_record(&line) {
+            Some(r) => (Some(r.at), r.text, r.stream),
+            None => {
+                let (at, text) = parse_line(&line);
+                (at, text, Stream::Stdout)
+            }
+        };
+        if let Some(t) = at {
+            executor.state_manager().timeline_mut().clock.advance_to(t);
+        }
+        // only the standard output carries hints
+        if stream.carries_hints() {
+            executor.feed_line(text);
+        } else if stream == Stream::Channel {
+            if let Some(m) = Message::parse(text) {
+                executor.feed_message(m);
+            }
+        }
+    }
+    info!("[replay]ends");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_parse_timestamp() {
+        let (t, s) = parse_line("[  12.500] Prog= 12.22%");
+        assert_eq!(t, Some(Duration::from_millis(12500)));
+        assert_eq!(s, "Prog= 12.22%");
+        let (t, s) = parse_line("PCOL");
+        assert_eq!(t, None);
+        assert_eq!(s, "PCOL");
+    }
+}
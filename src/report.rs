@@ -0,0 +1,25 @@
+use serde_json::{Map, Value};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// what a run measured, one section per part of the launcher, written as JSON at the end of the run
+#[derive(Default)]
+pub struct Report {
+    sections: Map<String, Value>,
+}
+
+impl Report {
+    pub fn new() -> Report {
+        Report::default()
+    }
+    pub fn set(&mut self, section: &str, v: Value) {
+        self.sections.insert(section.to_string(), v);
+    }
+    pub fn get(&self, section: &str) -> Option<&Value> {
+        self.sections.get(section)
+    }
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(&self.sections).unwrap())
+    }
+}
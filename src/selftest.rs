@@ -0,0 +1,227 @@
+use crate::state::{Knob, Target};
+use crate::StateManager;
+use serde_json::{json, Value};
+use std::fmt::{self, Display};
+use std::time::{Duration, Instant};
+
+const DEFAULT_SETTLE: Duration = Duration::from_secs(1);
+const DEFAULT_MIN_POWER_CHANGE: f64 = 5.0;
+/// how far the fans are moved from their start speed
+const FAN_PROBE_STEP: usize = 20;
+
+/// a check of every knob the strategy uses before the run, written as
+/// `"selftest": {"settle_ms": 1000, "min_power_change": 5}` in the application file
+#[derive(Clone, PartialEq, Debug)]
+pub struct SelfTest {
+    /// the time given to the hardware before the change is checked
+    pub settle: Duration,
+    /// without a read-back command, the power moving at least this much in W confirms the change
+    pub min_power_change: f64,
+}
+
+impl Default for SelfTest {
+    fn default() -> Self {
+        SelfTest { settle: DEFAULT_SETTLE, min_power_change: DEFAULT_MIN_POWER_CHANGE }
+    }
+}
+
+impl From<&Value> for SelfTest {
+    fn from(v: &Value) -> Self {
+        let obj = v.as_object().expect("selftest must be an object");
+        SelfTest {
+            settle: obj.get("settle_ms")
+                .map_or(DEFAULT_SETTLE, |x| Duration::from_millis(x.as_u64().expect("need a number of milisecond"))),
+            min_power_change: obj.get("min_power_change")
+                .map_or(DEFAULT_MIN_POWER_CHANGE, |x| x.as_f64().expect("min_power_change must be a number of W")),
+        }
+    }
+}
+
+/// the result of setting one knob
+#[derive(Clone, PartialEq, Debug)]
+pub struct Check {
+    pub knob: Knob,
+    pub from: Option<usize>,
+    pub to: usize,
+    /// the time taken by the command
+    pub latency: Duration,
+    /// what shows the change, or why there is none
+    pub evidence: String,
+    pub ok: bool,
+    /// the change is seen, a power which does not move at idle proves nothing either way
+    pub confirmed: bool,
+}
+
+impl Check {
+    pub fn to_value(&self) -> Value {
+        json!({
+            "knob": self.knob.key(),
+            "from": self.from,
+            "to": self.to,
+            "latency_ms": self.latency.as_secs_f64() * 1000.0,
+            "evidence": self.evidence,
+            "ok": self.ok,
+            "confirmed": self.confirmed,
+        })
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match (self.ok, self.confirmed) {
+            (false, _) => "FAILED",
+            (true, true) => "ok",
+            (true, false) => "unconfirmed",
+        };
+        write!(f, "{} {}: {} -> {}{} in {:?}, {}", status, self.knob.key(),
+            self.from.map_or("?".to_string(), |x| x.to_string()), self.to, self.knob.unit(), self.latency, self.evidence)
+    }
+}
+
+/// the knobs set by the actions of a strategy, in the order of `Knob::ALL`
+pub fn knobs_used(strategy: &Value) -> Vec<Knob> {
+    let keys: Vec<&String> = strategy.as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|a| a["action"].as_array())
+        .flatten()
+        .filter_map(|s| s.as_object())
+        .flat_map(|s| s.keys())
+        .collect();
+    Knob::ALL.iter().copied().filter(|k| keys.iter().any(|x| *x == k.key())).collect()
+}
+
+/// a value of `knob` other than `from`, taken from the frequency table when there is one
+pub fn probe_value(knob: Knob, from: usize, table: Option<&[usize]>) -> usize {
+    if let Some(t) = table {
+        let lower = t.iter().copied().filter(|x| *x < from).max();
+        let higher = t.iter().copied().filter(|x| *x > from).min();
+        if let Some(x) = lower.or(higher) {
+            return x;
+        }
+    }
+    match knob {
+        Knob::FanSpeed if from > 50 => from - FAN_PROBE_STEP,
+        Knob::FanSpeed => from + FAN_PROBE_STEP,
+        _ => from * 9 / 10,
+    }
+}
+
+impl SelfTest {
+    /// set a distinct value of `knob` on the whole cluster, check that it took effect and set it back
+    /// `power` reads the power of the cluster in W
+    pub fn check(&self, state_manager: &mut StateManager, power: &dyn Fn() -> usize, knob: Knob) -> Check {
+        let target = Target::default();
+        let from = state_manager.current(knob, &target);
+        let start = match from {
+            Some(x) => x,
+            None => return Check {
+                knob,
+                from,
+                to: 0,
+                latency: Duration::ZERO,
+                evidence: "the start state has no value to go back to".to_string(),
+                ok: false,
+                confirmed: false,
+            },
+        };
+        // the value actually sent, a probe out of the envelope is clamped
+        let to = state_manager.envelope().clamp(knob, probe_value(knob, start, state_manager.frequency_table(knob)));
+        let before = power();
+        let t = Instant::now();
+        let sent = state_manager.set(knob, &target, to);
+        let latency = t.elapsed();
+        state_manager.timeline_mut().clock.sleep(self.settle);
+        let (mut ok, confirmed, mut evidence) = match state_manager.readback().read(knob) {
+            _ if !sent => (false, false, "the commands to set it failed".to_string()),
+            Some(Ok(values)) => {
+                let agrees = state_manager.readback().agrees(to, &values);
+                (agrees, agrees, format!("read back {:?}", values))
+            }
+            Some(Err(e)) => (false, false, e),
+            // the power is only a hint, it may not move at idle
+            None => {
+                let after = power();
+                let change = (after as f64 - before as f64).abs();
+                (true, change >= self.min_power_change, format!("the power goes from {}W to {}W", before, after))
+            }
+        };
+        if !state_manager.set(knob, &target, start) {
+            ok = false;
+            evidence.push_str(", the commands to set it back failed");
+        }
+        let check = Check { knob, from, to, latency, evidence, ok, confirmed };
+        state_manager.timeline_mut().record("selftest", format!("{}", check));
+        check
+    }
+    /// check the knobs in order and stop at the first one which does not respond
+    pub fn run(&self, state_manager: &mut StateManager, power: &dyn Fn() -> usize, knobs: &[Knob]) -> Vec<Check> {
+        let mut checks = vec![];
+        for knob in knobs {
+            let check = self.check(state_manager, power, *knob);
+            let ok = check.ok;
+            checks.push(check);
+            if !ok {
+                break;
+            }
+        }
+        checks
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::envelope::Envelope;
+    use crate::readback::ReadBack;
+    use crate::health::Retry;
+    use crate::timeline::Clock;
+    use crate::State;
+    #[test]
+    fn test_knobs_used() {
+        let v = serde_json::from_str(r#"[
+            {"hint": "PCOL", "action": [{"GPU_Freq": 825, "Time": 0}, {"Wait": "progress > 50"}]},
+            {"phase": "end", "action": [{"Fan_Speed": "+10"}]}
+        ]"#).unwrap();
+        assert_eq!(knobs_used(&v), vec![Knob::GpuFreq, Knob::FanSpeed]);
+        assert_eq!(probe_value(Knob::GpuFreq, 390, Some(&[390, 405, 420])), 405);
+        assert_eq!(probe_value(Knob::FanSpeed, 80, None), 60);
+    }
+    #[test]
+    fn test_selftest() {
+        let mut sm = StateManager::dry_run(State::new(Some(900), Some(390), Some(40), None), Clock::Virtual(Duration::ZERO));
+        let v = serde_json::from_str(r#"{"commands": {"Fan_Speed": "echo 60", "CPU_Freq": "echo 900"}}"#).unwrap();
+        sm.set_readback(ReadBack::from(&v));
+        let selftest = SelfTest::default();
+        // the fans are read back, the GPU only has the power, which never moves here, the CPU keeps its value
+        let checks = selftest.run(&mut sm, &|| 1000, &[Knob::FanSpeed, Knob::GpuFreq, Knob::CpuFreq, Knob::GpuMemFreq]);
+        assert_eq!(checks.len(), 3);
+        assert!(checks[0].ok && checks[0].confirmed);
+        assert_eq!((checks[0].from, checks[0].to), (Some(40), 60));
+        assert!(checks[1].ok && !checks[1].confirmed);
+        assert!(!checks[2].ok);
+        assert_eq!(sm.current(Knob::FanSpeed, &Target::default()), Some(40));
+        assert_eq!(sm.current(Knob::GpuFreq, &Target::default()), Some(390));
+    }
+    #[test]
+    fn test_selftest_failed_or_clamped() {
+        let mut sm = StateManager::dry_run(State::new(Some(900), Some(390), Some(40), None), Clock::Virtual(Duration::ZERO));
+        let v = serde_json::from_str(r#"{"commands": {"Fan_Speed": "echo 55"}}"#).unwrap();
+        sm.set_readback(ReadBack::from(&v));
+        // the probe of the fans, 60, is out of the envelope, 55 is read back
+        let v = serde_json::from_str(r#"{"ranges": {"Fan_Speed": [30, 55]}}"#).unwrap();
+        sm.set_envelope(Envelope::from(&v));
+        let selftest = SelfTest::default();
+        let checks = selftest.run(&mut sm, &|| 1000, &[Knob::FanSpeed]);
+        assert!(checks[0].ok && checks[0].confirmed);
+        assert_eq!(checks[0].to, 55);
+        // the fans are read back at the speed asked for, but the command never reached them
+        let v = serde_json::from_str(r#"{"commands": {"Fan_Speed": "echo 55"}}"#).unwrap();
+        sm.set_readback(ReadBack::from(&v));
+        let v = serde_json::from_str(r#"{"attempts": 1, "backoff_ms": 0}"#).unwrap();
+        sm.set_retry(Retry::from(&v));
+        sm.set_runner(|c| if c.contains("FAN") { Err("ipmi timeout".to_string()) } else { Ok(()) });
+        let checks = selftest.run(&mut sm, &|| 1000, &[Knob::FanSpeed]);
+        assert!(!checks[0].ok && !checks[0].confirmed);
+    }
+}
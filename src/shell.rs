@@ -0,0 +1,48 @@
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+
+/// run `command` with `sh -c` in a process group of its own,
+/// so that `kill` also stops what it starts, such as `gpu_burn 60; sleep 10`
+pub fn spawn(command: &str) -> io::Result<Child> {
+    Command::new("sh").arg("-c").arg(command).process_group(0).spawn()
+}
+
+/// kill the whole process group of a child started by `spawn` and reap it
+pub fn kill(child: &mut Child) {
+    let _ = killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL);
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+    #[test]
+    fn test_kill_group() {
+        let file = std::env::temp_dir().join(format!("app_launcher.shell.{}", std::process::id()));
+        let mut child = spawn(&format!("sleep 30 & echo $! > {}; wait", file.display())).unwrap();
+        let start = Instant::now();
+        let pid = loop {
+            if let Some(x) = fs::read_to_string(&file).ok().and_then(|s| s.trim().parse::<u32>().ok()) {
+                break x;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+            sleep(Duration::from_millis(10));
+        };
+        kill(&mut child);
+        let _ = fs::remove_file(&file);
+        // the sleep is gone, or a zombie waiting for init
+        let alive = || fs::read_to_string(format!("/proc/{}/stat", pid)).is_ok_and(|s| !s.contains(") Z"));
+        let start = Instant::now();
+        while alive() && start.elapsed() < Duration::from_secs(2) {
+            sleep(Duration::from_millis(10));
+        }
+        assert!(!alive());
+    }
+}
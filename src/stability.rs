@@ -0,0 +1,431 @@
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_TOLERANCE: f64 = 30.0;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(600);
+/// the weight of a new reading in the moving average
+const DEFAULT_EWMA_ALPHA: f64 = 0.3;
+
+/// how much the power may move inside the window
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Tolerance {
+    /// in W, written as `"tolerance": 30`
+    Absolute(f64),
+    /// a fraction of the mean, written as `"tolerance": "2%"`
+    Relative(f64),
+}
+
+impl Tolerance {
+    fn parse(v: &Value) -> Tolerance {
+        match v {
+            Value::String(s) => Tolerance::parse_str(s).expect("need a tolerance like \"2%\""),
+            x => Tolerance::Absolute(x.as_f64().expect("need a tolerance in W or a string like \"2%\"")),
+        }
+    }
+    /// `30`, `30W` or `2%`
+    fn parse_str(s: &str) -> Option<Tolerance> {
+        let s = s.trim();
+        match s.strip_suffix('%') {
+            Some(x) => x.trim().parse().ok().map(|x: f64| Tolerance::Relative(x / 100.0)),
+            None => s.trim_end_matches('W').trim().parse().ok().map(Tolerance::Absolute),
+        }
+    }
+    pub fn allows(&self, spread: f64, mean: f64) -> bool {
+        match self {
+            Tolerance::Absolute(x) => spread <= *x,
+            Tolerance::Relative(x) => spread <= x * mean,
+        }
+    }
+}
+
+impl Display for Tolerance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tolerance::Absolute(x) => write!(f, "{}W", x),
+            Tolerance::Relative(x) => write!(f, "{}%", x * 100.0),
+        }
+    }
+}
+
+/// one way to tell the power is stable, written as `"slope < 5"`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Criterion {
+    /// the highest minus the lowest power of the window
+    Range(Tolerance),
+    /// the slope of the linear regression of the window, in W/min
+    Slope(f64),
+    /// the standard deviation of the window, in W
+    StdDev(f64),
+    /// the highest minus the lowest moving average of the window
+    Ewma(Tolerance),
+    /// the highest minus the lowest temperature of the window, in °C, ignored without `temperature_command`
+    Temperature(f64),
+}
+
+impl Criterion {
+    pub fn parse(s: &str) -> Result<Criterion, String> {
+        let s = s.trim();
+        let (name, rest) = s.split_once(char::is_whitespace).ok_or(format!("can not understand the criterion {}", s))?;
+        let rest = rest.trim_start();
+        let value = rest.strip_prefix("<=").or_else(|| rest.strip_prefix('<'))
+            .ok_or(format!("a criterion is written as {} < value", name))?;
+        let tolerance = || Tolerance::parse_str(value).ok_or(format!("need a number or a percentage in {}", s));
+        let number = || value.trim().trim_end_matches(|c: char| c.is_alphabetic() || c == '/' || c == '°')
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| format!("need a number in {}", s));
+        match name {
+            "range" => Ok(Criterion::Range(tolerance()?)),
+            "slope" => Ok(Criterion::Slope(number()?)),
+            "stddev" => Ok(Criterion::StdDev(number()?)),
+            "ewma" => Ok(Criterion::Ewma(tolerance()?)),
+            "temperature" => Ok(Criterion::Temperature(number()?)),
+            _ => Err(format!("unknown criterion {}, use range, slope, stddev, ewma or temperature", name)),
+        }
+    }
+    /// `None` when the window has nothing to tell, i.e. no temperature
+    pub fn holds(&self, w: &Window) -> Option<bool> {
+        match self {
+            Criterion::Range(t) => Some(t.allows(w.max() - w.min(), w.mean())),
+            Criterion::Slope(x) => Some(w.slope().abs() <= *x),
+            Criterion::StdDev(x) => Some(w.stddev() <= *x),
+            Criterion::Ewma(t) => Some(t.allows(w.ewma_spread(), w.mean())),
+            Criterion::Temperature(x) => w.temperature_spread().map(|t| t <= *x),
+        }
+    }
+}
+
+impl Display for Criterion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Criterion::Range(t) => write!(f, "range < {}", t),
+            Criterion::Slope(x) => write!(f, "slope < {}W/min", x),
+            Criterion::StdDev(x) => write!(f, "stddev < {}W", x),
+            Criterion::Ewma(t) => write!(f, "ewma < {}", t),
+            Criterion::Temperature(x) => write!(f, "temperature < {}C", x),
+        }
+    }
+}
+
+/// how the criteria are put together
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Combine {
+    All,
+    Any,
+}
+
+/// what to do when the power does not settle before the timeout
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OnTimeout {
+    Proceed,
+    Abort,
+}
+
+/// when the power is considered stable before the application starts, written in the application file as
+/// `"stability": {"window_s": 10, "interval_ms": 1000, "tolerance": 30, "timeout_s": 600, "on_timeout": "proceed"}`
+/// or with `"criteria": ["slope < 5", "stddev < 8"], "combine": "all"` instead of `"tolerance"`
+#[derive(Clone, PartialEq, Debug)]
+pub struct Stability {
+    /// how long the power must stay stable
+    pub window: Duration,
+    /// the time between two readings of the power
+    pub interval: Duration,
+    pub criteria: Vec<Criterion>,
+    pub combine: Combine,
+    pub ewma_alpha: f64,
+    /// a shell command printing temperatures, the highest one is used
+    pub temperature_command: Option<String>,
+    /// no timeout waits forever
+    pub timeout: Option<Duration>,
+    pub on_timeout: OnTimeout,
+}
+
+impl Default for Stability {
+    fn default() -> Self {
+        Stability {
+            window: DEFAULT_WINDOW,
+            interval: DEFAULT_INTERVAL,
+            criteria: vec![Criterion::Range(Tolerance::Absolute(DEFAULT_TOLERANCE))],
+            combine: Combine::All,
+            ewma_alpha: DEFAULT_EWMA_ALPHA,
+            temperature_command: None,
+            timeout: Some(DEFAULT_TIMEOUT),
+            on_timeout: OnTimeout::Proceed,
+        }
+    }
+}
+
+impl From<&Value> for Stability {
+    fn from(v: &Value) -> Self {
+        let obj = v.as_object().expect("stability must be an object");
+        let default = Stability::default();
+        let seconds = |key: &str| obj.get(key).map(|x| Duration::from_secs_f64(x.as_f64().expect("need a number of second")));
+        let mut criteria: Vec<Criterion> = obj.get("criteria").map_or(vec![], |x| {
+            x.as_array()
+                .expect("criteria must be an array")
+                .iter()
+                .map(|c| Criterion::parse(c.as_str().expect("a criterion must be a string")).unwrap_or_else(|e| panic!("{}", e)))
+                .collect()
+        });
+        if let Some(t) = obj.get("tolerance") {
+            criteria.insert(0, Criterion::Range(Tolerance::parse(t)));
+        }
+        if criteria.is_empty() {
+            criteria = default.criteria;
+        }
+        Stability {
+            window: seconds("window_s").unwrap_or(default.window),
+            interval: obj.get("interval_ms")
+                .map(|x| Duration::from_millis(x.as_u64().expect("need a number of milisecond")))
+                .unwrap_or(default.interval),
+            criteria,
+            combine: match obj.get("combine").map(|x| x.as_str().expect("combine must be a string")) {
+                None | Some("all") => Combine::All,
+                Some("any") => Combine::Any,
+                Some(x) => panic!("unknown combine {}, use all or any", x),
+            },
+            ewma_alpha: obj.get("ewma_alpha").map_or(default.ewma_alpha, |x| {
+                let a = x.as_f64().expect("ewma_alpha must be a number");
+                assert!(a > 0.0 && a <= 1.0, "ewma_alpha must be in (0, 1]");
+                a
+            }),
+            temperature_command: obj.get("temperature_command")
+                .map(|x| x.as_str().expect("temperature_command must be a string").to_string()),
+            timeout: match obj.get("timeout_s") {
+                Some(Value::Null) => None,
+                Some(_) => seconds("timeout_s"),
+                None => default.timeout,
+            },
+            on_timeout: match obj.get("on_timeout").map(|x| x.as_str().expect("on_timeout must be a string")) {
+                None | Some("proceed") => OnTimeout::Proceed,
+                Some("abort") => OnTimeout::Abort,
+                Some(x) => panic!("unknown on_timeout {}, use proceed or abort", x),
+            },
+        }
+    }
+}
+
+impl Stability {
+    /// the number of readings in a window, at least 2
+    pub fn window_len(&self) -> usize {
+        let interval = self.interval.as_millis().max(1);
+        ((self.window.as_millis() / interval) as usize).max(2)
+    }
+    pub fn window(&self) -> Window {
+        Window::new(self.window_len(), self.interval, self.ewma_alpha)
+    }
+    /// whether the full window `w` meets the criteria, the ones without data are left out
+    pub fn holds(&self, w: &Window) -> bool {
+        let known: Vec<bool> = self.criteria.iter().filter_map(|c| c.holds(w)).collect();
+        match self.combine {
+            _ if known.is_empty() => true,
+            Combine::All => known.iter().all(|x| *x),
+            Combine::Any => known.iter().any(|x| *x),
+        }
+    }
+}
+
+/// the last readings of the power, and of the temperature when there is one
+pub struct Window {
+    samples: VecDeque<f64>,
+    /// the moving average at each reading of `samples`
+    ewma: VecDeque<f64>,
+    temperatures: VecDeque<f64>,
+    len: usize,
+    interval: Duration,
+    alpha: f64,
+}
+
+fn push_bounded(q: &mut VecDeque<f64>, x: f64, len: usize) {
+    q.push_back(x);
+    if q.len() > len {
+        q.pop_front();
+    }
+}
+
+fn spread(q: &VecDeque<f64>) -> f64 {
+    q.iter().copied().fold(f64::NEG_INFINITY, f64::max) - q.iter().copied().fold(f64::INFINITY, f64::min)
+}
+
+impl Window {
+    pub fn new(len: usize, interval: Duration, alpha: f64) -> Window {
+        Window {
+            samples: VecDeque::with_capacity(len + 1),
+            ewma: VecDeque::with_capacity(len + 1),
+            temperatures: VecDeque::with_capacity(len + 1),
+            len,
+            interval,
+            alpha,
+        }
+    }
+    pub fn push(&mut self, x: f64) {
+        let e = match self.ewma.back() {
+            Some(last) => self.alpha * x + (1.0 - self.alpha) * last,
+            None => x,
+        };
+        push_bounded(&mut self.samples, x, self.len);
+        push_bounded(&mut self.ewma, e, self.len);
+    }
+    pub fn push_temperature(&mut self, x: f64) {
+        push_bounded(&mut self.temperatures, x, self.len);
+    }
+    pub fn is_full(&self) -> bool {
+        self.samples.len() == self.len
+    }
+    pub fn min(&self) -> f64 {
+        self.samples.iter().copied().fold(f64::INFINITY, f64::min)
+    }
+    pub fn max(&self) -> f64 {
+        self.samples.iter().copied().fold(f64::NEG_INFINITY, f64::max)
+    }
+    pub fn mean(&self) -> f64 {
+        self.samples.iter().sum::<f64>() / self.samples.len().max(1) as f64
+    }
+    pub fn stddev(&self) -> f64 {
+        let mean = self.mean();
+        (self.samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / self.samples.len().max(1) as f64).sqrt()
+    }
+    /// the slope of the least squares line through the readings, in W/min
+    pub fn slope(&self) -> f64 {
+        let n = self.samples.len() as f64;
+        if n < 2.0 {
+            return 0.0;
+        }
+        let mean_i = (n - 1.0) / 2.0;
+        let mean = self.mean();
+        let (mut num, mut den) = (0.0, 0.0);
+        for (i, x) in self.samples.iter().enumerate() {
+            num += (i as f64 - mean_i) * (x - mean);
+            den += (i as f64 - mean_i).powi(2);
+        }
+        num / den * 60.0 / self.interval.as_secs_f64().max(f64::EPSILON)
+    }
+    pub fn ewma_spread(&self) -> f64 {
+        spread(&self.ewma)
+    }
+    /// `None` until there is a temperature for every reading of the window
+    pub fn temperature_spread(&self) -> Option<f64> {
+        if self.temperatures.len() < self.len {
+            return None;
+        }
+        Some(spread(&self.temperatures))
+    }
+    pub fn evidence(&self, stable: bool, waited: Duration, readings: usize) -> Evidence {
+        Evidence {
+            stable,
+            waited,
+            readings,
+            min: self.min(),
+            max: self.max(),
+            mean: self.mean(),
+            stddev: self.stddev(),
+            slope: self.slope(),
+            temperature_spread: self.temperature_spread(),
+        }
+    }
+}
+
+/// what the wait for stability saw in its last window
+#[derive(Clone, PartialEq, Debug)]
+pub struct Evidence {
+    /// false when the timeout came first
+    pub stable: bool,
+    pub waited: Duration,
+    /// the number of readings of the whole wait
+    pub readings: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub stddev: f64,
+    /// in W/min
+    pub slope: f64,
+    pub temperature_spread: Option<f64>,
+}
+
+impl Evidence {
+    pub fn to_value(&self) -> Value {
+        json!({
+            "stable": self.stable,
+            "waited_s": self.waited.as_secs_f64(),
+            "readings": self.readings,
+            "window_min_w": self.min,
+            "window_max_w": self.max,
+            "window_mean_w": self.mean,
+            "window_stddev_w": self.stddev,
+            "window_slope_w_per_min": self.slope,
+            "window_temperature_spread_c": self.temperature_spread,
+        })
+    }
+}
+
+impl Display for Evidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} after {:?} ({} readings), the last window is {}W-{}W, {:.1}W on average, stddev {:.1}W, slope {:.1}W/min",
+            if self.stable { "stable" } else { "not stable" }, self.waited, self.readings,
+            self.min, self.max, self.mean, self.stddev, self.slope)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn window(samples: &[f64]) -> Window {
+        let mut w = Window::new(samples.len(), Duration::from_secs(1), 0.5);
+        for x in samples {
+            w.push(*x);
+        }
+        w
+    }
+    #[test]
+    fn test_stability_from_value() {
+        let v = serde_json::from_str(r#"{"window_s": 5, "interval_ms": 500, "tolerance": "2%", "timeout_s": null, "on_timeout": "abort"}"#).unwrap();
+        let s = Stability::from(&v);
+        assert_eq!(s, Stability {
+            window: Duration::from_secs(5),
+            interval: Duration::from_millis(500),
+            criteria: vec![Criterion::Range(Tolerance::Relative(0.02))],
+            timeout: None,
+            on_timeout: OnTimeout::Abort,
+            ..Stability::default()
+        });
+        assert_eq!(s.window_len(), 10);
+        assert_eq!(Stability::from(&serde_json::json!({})), Stability::default());
+    }
+    #[test]
+    fn test_window() {
+        let mut w = Window::new(3, Duration::from_secs(1), 0.3);
+        for x in [1500.0, 1210.0, 1190.0] {
+            w.push(x);
+        }
+        assert!(w.is_full());
+        assert!(!Tolerance::Absolute(30.0).allows(w.max() - w.min(), w.mean()));
+        w.push(1200.0);
+        assert_eq!((w.min(), w.max(), w.mean()), (1190.0, 1210.0, 1200.0));
+        assert!(Tolerance::Absolute(30.0).allows(w.max() - w.min(), w.mean()));
+        assert!(!Tolerance::Relative(0.01).allows(w.max() - w.min(), w.mean()));
+    }
+    #[test]
+    fn test_criteria() {
+        assert_eq!(Criterion::parse("slope < 5 W/min"), Ok(Criterion::Slope(5.0)));
+        assert_eq!(Criterion::parse("ewma <= 2%"), Ok(Criterion::Ewma(Tolerance::Relative(0.02))));
+        assert!(Criterion::parse("slope > 5").is_err());
+        assert!(Criterion::parse("humidity < 5").is_err());
+        // a steady climb of 1W/s: small range, but a slope of 60W/min
+        let climbing = window(&[1200.0, 1201.0, 1202.0, 1203.0, 1204.0]);
+        assert_eq!(climbing.slope(), 60.0);
+        let v = serde_json::from_str(r#"{"criteria": ["range < 30", "slope < 10"], "combine": "all"}"#).unwrap();
+        let mut s = Stability::from(&v);
+        assert!(!s.holds(&climbing));
+        s.combine = Combine::Any;
+        assert!(s.holds(&climbing));
+        // one noisy reading breaks the range, not the standard deviation
+        let noisy = window(&[1200.0, 1201.0, 1260.0, 1199.0, 1200.0, 1201.0, 1200.0, 1199.0, 1200.0, 1201.0]);
+        assert!(!Criterion::Range(Tolerance::Absolute(30.0)).holds(&noisy).unwrap());
+        assert_eq!(Criterion::StdDev(20.0).holds(&noisy), Some(true));
+        assert_eq!(Criterion::Temperature(1.0).holds(&noisy), None);
+    }
+}
@@ -1,14 +1,23 @@
-use log::info;
+use log::{error, info, warn};
 use power_controller::pwrctl::Command;
 use power_controller::Cluster;
-use serde_json::Value;
+use serde_json::{Map, Value};
 use num::*;
+use crate::timeline::{Timeline, Clock};
+use crate::ramp::{self, Ramp};
+use crate::expr::{Captures, Expression};
+use crate::readback::ReadBack;
+use crate::health::{self, Health, OnFailure, Retry};
+use crate::envelope::Envelope;
+use crate::fan::{self, FanCurve, Input, Sensor};
+use crate::shell;
 use std::{
-    thread::{
-        sleep
-    },
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    thread,
     time::{
-        Duration
+        Duration,
+        Instant
     }, 
     fmt::{
         self,
@@ -17,58 +26,296 @@ use std::{
 };
 
 const DEFAULT_LASTING_TIME: Duration = Duration::from_millis(1);
+const SHELL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// how long a power reading is used again before the cluster is asked anew
+const POWER_MAX_AGE: Duration = Duration::from_millis(100);
+/// a command of a switch and what it sets, a knob on the whole cluster (`None`) or on a device
+type Planned = ((Knob, Option<String>), String);
+/// runs one command in place of the power controller
+type Runner = Box<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+/// the shortest wait for a deferred switch, so that the clock always moves
+const MIN_DEFER_STEP: Duration = Duration::from_millis(1);
 
-#[derive(Clone, PartialEq)]
+/// a part of a switch held back by the envelope
+#[derive(Clone, Debug)]
+enum Held {
+    Value(Knob, usize),
+    Ramp(Ramp),
+}
+
+impl Held {
+    fn knob(&self) -> Knob {
+        match self {
+            Held::Value(k, _) => *k,
+            Held::Ramp(r) => r.knob,
+        }
+    }
+}
+
+/// a setting of the cluster that a state can change
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Knob {
+    CpuFreq,
+    GpuFreq,
+    FanSpeed,
+    GpuPowerLimit,
+    GpuMemFreq,
+    CpuPowerCap,
+    CpuUncoreFreq,
+}
+
+impl Knob {
+    pub const ALL: [Knob; 7] = [
+        Knob::CpuFreq, Knob::GpuFreq, Knob::FanSpeed,
+        Knob::GpuPowerLimit, Knob::GpuMemFreq, Knob::CpuPowerCap, Knob::CpuUncoreFreq
+    ];
+    /// knobs added later, a start state may leave them out
+    pub const OPTIONAL: [Knob; 4] = [Knob::GpuPowerLimit, Knob::GpuMemFreq, Knob::CpuPowerCap, Knob::CpuUncoreFreq];
+    /// the name in the application file
+    pub fn key(&self) -> &'static str {
+        match self {
+            Knob::CpuFreq => "CPU_Freq",
+            Knob::GpuFreq => "GPU_Freq",
+            Knob::FanSpeed => "Fan_Speed",
+            Knob::GpuPowerLimit => "GPU_Power_Limit",
+            Knob::GpuMemFreq => "GPU_Mem_Freq",
+            Knob::CpuPowerCap => "CPU_Power_Cap",
+            Knob::CpuUncoreFreq => "CPU_Uncore_Freq",
+        }
+    }
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Knob::CpuFreq | Knob::GpuFreq | Knob::GpuMemFreq | Knob::CpuUncoreFreq => "MHz",
+            Knob::FanSpeed => "%",
+            Knob::GpuPowerLimit | Knob::CpuPowerCap => "W",
+        }
+    }
+    pub fn from_key(key: &str) -> Option<Knob> {
+        Knob::ALL.iter().copied().find(|k| k.key() == key)
+    }
+    /// the kind of device in commands for the power controller
+    pub fn device(&self) -> &'static str {
+        match self {
+            Knob::CpuFreq | Knob::CpuPowerCap | Knob::CpuUncoreFreq => "CPU",
+            Knob::GpuFreq | Knob::GpuPowerLimit | Knob::GpuMemFreq => "GPU",
+            Knob::FanSpeed => "FAN",
+        }
+    }
+    /// the command setting the knob of `device`, such as `SETFREQ GPU@node1.0 825`
+    pub fn command(&self, device: &str, value: usize) -> String {
+        match self {
+            Knob::CpuFreq | Knob::GpuFreq => format!("SETFREQ {} {}", device, value),
+            Knob::FanSpeed => format!("SETSPEED {} {}", device, value),
+            Knob::GpuPowerLimit | Knob::CpuPowerCap => format!("SETPOWER {} {}", device, value),
+            Knob::GpuMemFreq => format!("SETMEMFREQ {} {}", device, value),
+            Knob::CpuUncoreFreq => format!("SETUNCOREFREQ {} {}", device, value),
+        }
+    }
+    /// the command giving the knob back to the hardware default, such as `RESET GPU`
+    pub fn reset_command(&self) -> String {
+        match self {
+            Knob::CpuFreq | Knob::GpuFreq | Knob::FanSpeed => format!("RESET {}", self.device()),
+            Knob::GpuPowerLimit | Knob::CpuPowerCap => format!("RESETPOWER {}", self.device()),
+            Knob::GpuMemFreq => format!("RESETMEMFREQ {}", self.device()),
+            Knob::CpuUncoreFreq => format!("RESETUNCOREFREQ {}", self.device()),
+        }
+    }
+    /// the commands resetting every knob at the end of a run, the fans first
+    pub fn reset_commands() -> Vec<String> {
+        [Knob::FanSpeed, Knob::GpuFreq, Knob::CpuFreq].iter()
+            .chain(Knob::OPTIONAL.iter())
+            .map(|k| k.reset_command())
+            .collect()
+    }
+}
+
+/// which part of the cluster a state applies to, the whole cluster by default
+/// written as `"Node": "node1"`, `"GPUs": [0]`, `"Fans": [0, 1]` in a state
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Target {
+    /// the `name` of a server in the cluster file
+    pub node: Option<String>,
+    pub gpus: Option<Vec<usize>>,
+    pub fans: Option<Vec<usize>>,
+}
+
+impl Target {
+    fn from_object(obj: &Map<String, Value>) -> Target {
+        let indices = |key: &str| obj.get(key).map(|x| {
+            x.as_array()
+                .expect("need an array of index")
+                .iter()
+                .map(|i| i.as_u64().expect("an index must be a number").to_usize().unwrap())
+                .collect::<Vec<usize>>()
+        });
+        Target {
+            node: obj.get("Node").map(|x| x.as_str().expect("Node must be the name of a server").to_string()),
+            gpus: indices("GPUs"),
+            fans: indices("Fans"),
+        }
+    }
+    pub fn is_cluster_wide(&self) -> bool {
+        self.node.is_none() && self.gpus.is_none() && self.fans.is_none()
+    }
+    /// whether the target is a part of the cluster for `knob`
+    pub fn narrows(&self, knob: Knob) -> bool {
+        self.node.is_some() || match knob.device() {
+            "GPU" => self.gpus.is_some(),
+            "FAN" => self.fans.is_some(),
+            _ => false,
+        }
+    }
+    /// the devices to address for `knob`, such as `GPU@node1.0`, empty for the whole cluster
+    /// indices without a node apply to every node in `nodes`, CPU knobs only follow the node
+    /// so indices are empty too when no node is known, `narrows` tells the two apart
+    pub fn devices(&self, knob: Knob, nodes: &[String]) -> Vec<String> {
+        let indices = match knob.device() {
+            "GPU" => self.gpus.as_ref(),
+            "FAN" => self.fans.as_ref(),
+            _ => None,
+        };
+        let nodes: Vec<&str> = match &self.node {
+            Some(n) => vec![n.as_str()],
+            None if indices.is_some() => nodes.iter().map(|n| n.as_str()).collect(),
+            None => return vec![],
+        };
+        match indices {
+            Some(indices) => nodes.iter()
+                .flat_map(|n| indices.iter().map(move |i| format!("{}@{}.{}", knob.device(), n, i)))
+                .collect(),
+            None => nodes.iter().map(|n| format!("{}@{}", knob.device(), n)).collect(),
+        }
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Target: {}", self.node.as_deref().unwrap_or("*"))?;
+        if let Some(g) = &self.gpus {
+            write!(f, " GPU{:?}", g)?;
+        }
+        if let Some(x) = &self.fans {
+            write!(f, " FAN{:?}", x)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Default)]
 pub struct State {
     pub(super) cpu_freq: Option<usize>,
     pub(super) gpu_freq: Option<usize>,
     pub(super) fan_speed: Option<usize>,
     pub(super) lasting_time: Option<Duration>,
+    /// in W
+    pub(super) gpu_power_limit: Option<usize>,
+    pub(super) gpu_mem_freq: Option<usize>,
+    /// the package power of the CPU, in W
+    pub(super) cpu_power_cap: Option<usize>,
+    pub(super) cpu_uncore_freq: Option<usize>,
+    /// knobs changed gradually, after the other ones are set
+    pub(super) ramps: Vec<Ramp>,
+    /// knobs given relative to the current state, such as `"+75"`, resolved at switch time
+    pub(super) exprs: Vec<(Knob, Expression)>,
+    pub(super) target: Target,
+    /// `"Fan_Speed": "curve"` hands the fans over to the fan curve, any other fan speed takes them back
+    pub(super) fan_curve: bool,
 }
 
 pub struct StateManager<'a> {
     current_state: State,
-    cluster: &'a Cluster
+    /// the state the run starts at, the preparation comes back to it
+    start_state: State,
+    // no cluster means dry run: commands are only logged
+    cluster: Option<&'a Cluster>,
+    timeline: Timeline,
+    /// the values a knob supports, ramps only go through them
+    frequency_table: HashMap<Knob, Vec<usize>>,
+    /// the names of the servers in the cluster file
+    nodes: Vec<String>,
+    /// values set on a part of the cluster, they override `current_state`
+    device_state: HashMap<(Knob, String), usize>,
+    /// the values whose command failed, on the whole cluster (`None`) or a device, they are sent again
+    stale: HashSet<(Knob, Option<String>)>,
+    /// runs the commands instead of the power controller, see `set_runner`
+    runner: Option<Runner>,
+    /// how to read the values back from the hardware
+    readback: ReadBack,
+    /// the first value the hardware refused, when the run must stop for it
+    refused: Option<String>,
+    /// how failed commands are retried and what is done when a node keeps failing
+    retry: Retry,
+    /// the commands are sent from several threads at once
+    health: Mutex<Health>,
+    /// the last power read from the cluster and when
+    power: Mutex<Option<(Instant, usize)>>,
+    /// the cluster is held at the safe state of the retry policy
+    safe: bool,
+    /// the limits of the values and of the switches sent to the hardware
+    envelope: Envelope,
+    /// parts of switches held back by the envelope, sent once their component may change
+    deferred: Vec<(Target, Held)>,
+    fan_curve: Option<FanCurve>,
+    /// the fans follow the curve instead of the states
+    fan_curve_on: bool,
+    /// when the curve was evaluated last
+    fan_curve_at: Option<Duration>,
+    /// reads the temperature of a curve which follows it
+    fan_sensor: Option<Sensor>,
 }
 
 impl From<&Value> for State {
     fn from(c: &Value) -> Self {
         let obj = c.as_object().expect("the value in action must be an object");
-        State { 
-            cpu_freq: match obj.get("CPU_Freq") {
+        let mut ramps = vec![];
+        let mut exprs = vec![];
+        // a number sets the knob at once, an object ramps it, a string is an expression
+        let mut setting = |knob: Knob, msg: &str| {
+            match obj.get(knob.key()) {
                 None => {
                     None
                 },
-                Some(x) => {
-                    Some(x.as_u64().expect("need a number").to_usize().unwrap())
-                }
-            }, 
-            gpu_freq: match obj.get("GPU_Freq") {
-                None => {
+                Some(x) if x.is_object() => {
+                    ramps.push(Ramp::from_value(knob, x));
                     None
                 },
-                Some(x) => {
-                    Some(x.as_u64().expect("need a number").to_usize().unwrap())
-                }
-            }, 
-            fan_speed: 
-            match obj.get("Fan_Speed") {
-                None => {
+                Some(Value::String(x)) => {
+                    let e = Expression::parse(x)
+                        .unwrap_or_else(|e| panic!("can not parse {} of {}: {}", x, knob.key(), e));
+                    exprs.push((knob, e));
                     None
                 },
                 Some(x) => {
-                    Some(x.as_u64().expect("need a number[1-100]").to_usize().unwrap())
+                    Some(x.as_u64().expect(msg).to_usize().unwrap())
                 }
-            }, 
-            lasting_time: 
-            match obj.get("Time") {
-                None => {
-                    None
-                },
-                Some(x) => {
-                    Some(Duration::from_millis(x.as_u64().expect("need a number of milisecond")))
-                }
-            } 
+            }
+        };
+        let cpu_freq = setting(Knob::CpuFreq, "need a number");
+        let gpu_freq = setting(Knob::GpuFreq, "need a number");
+        let fan_curve = obj.get(Knob::FanSpeed.key()).and_then(|x| x.as_str()) == Some(fan::CURVE);
+        let fan_speed = if fan_curve {
+            None
+        } else {
+            setting(Knob::FanSpeed, "need a number[1-100]")
+        };
+        let gpu_power_limit = setting(Knob::GpuPowerLimit, "need a number of W");
+        let gpu_mem_freq = setting(Knob::GpuMemFreq, "need a number");
+        let cpu_power_cap = setting(Knob::CpuPowerCap, "need a number of W");
+        let cpu_uncore_freq = setting(Knob::CpuUncoreFreq, "need a number");
+        State { 
+            cpu_freq, 
+            gpu_freq, 
+            fan_speed, 
+            gpu_power_limit,
+            gpu_mem_freq,
+            cpu_power_cap,
+            cpu_uncore_freq,
+            ramps,
+            exprs,
+            target: Target::from_object(obj),
+            fan_curve,
+            lasting_time: obj.get("Time")
+                .map(|x| Duration::from_millis(x.as_u64().expect("need a number of milisecond"))),
         }
     }
 }
@@ -76,28 +323,44 @@ impl State {
     // maybe there are some places can be empty
     pub fn new(cpu_freq: Option<usize>, gpu_freq: Option<usize>, fan_speed: Option<usize>, lasting_time: Option<Duration>) -> State {
         State {
-            cpu_freq: cpu_freq,
-            gpu_freq: gpu_freq,
-            fan_speed: fan_speed,
-            lasting_time: lasting_time
+            cpu_freq,
+            gpu_freq,
+            fan_speed,
+            lasting_time,
+            ..State::default()
         }
     }
-    pub fn all_filled(&self) ->bool {
-        match self.cpu_freq {
-            None => false,
-            Some(_) => {
-                match self.gpu_freq {
-                    None => false,
-                    Some(_) => {
-                        match self.fan_speed {
-                            None => false,
-                            Some(_) => true
-                        } 
-                    }
-                }
-            }
+    pub fn get(&self, knob: Knob) -> Option<usize> {
+        match knob {
+            Knob::CpuFreq => self.cpu_freq,
+            Knob::GpuFreq => self.gpu_freq,
+            Knob::FanSpeed => self.fan_speed,
+            Knob::GpuPowerLimit => self.gpu_power_limit,
+            Knob::GpuMemFreq => self.gpu_mem_freq,
+            Knob::CpuPowerCap => self.cpu_power_cap,
+            Knob::CpuUncoreFreq => self.cpu_uncore_freq,
+        }
+    }
+    /// the knobs the state changes, by a value, an expression or a ramp
+    pub fn knobs(&self) -> Vec<Knob> {
+        Knob::ALL.iter().copied()
+            .filter(|k| self.get(*k).is_some() || self.exprs.iter().any(|(x, _)| x == k) || self.ramps.iter().any(|r| r.knob == *k))
+            .collect()
+    }
+    pub fn set(&mut self, knob: Knob, value: Option<usize>) {
+        match knob {
+            Knob::CpuFreq => self.cpu_freq = value,
+            Knob::GpuFreq => self.gpu_freq = value,
+            Knob::FanSpeed => self.fan_speed = value,
+            Knob::GpuPowerLimit => self.gpu_power_limit = value,
+            Knob::GpuMemFreq => self.gpu_mem_freq = value,
+            Knob::CpuPowerCap => self.cpu_power_cap = value,
+            Knob::CpuUncoreFreq => self.cpu_uncore_freq = value,
         }
     }
+    pub fn all_filled(&self) ->bool {
+        self.cpu_freq.is_some() && self.gpu_freq.is_some() && self.fan_speed.is_some()
+    }
 }
 
 impl Display for State {
@@ -122,6 +385,9 @@ impl Display for State {
             Some(x) => {
                 format!("Fan_Speed: {}%,", x)
             },
+            None if self.fan_curve => {
+                format!("Fan_Speed: {},", fan::CURVE)
+            },
             None => {
                 String::new()
             }
@@ -134,125 +400,633 @@ impl Display for State {
                 String::new()
             }
         };
-        write!(f, "State{{{}{}{}{}}}", cpu_freq, gpu_freq, fan_speed, lasting_time)
+        let others: String = Knob::OPTIONAL.iter()
+            .filter_map(|k| self.get(*k).map(|x| format!("{}: {}{},", k.key(), x, k.unit())))
+            .collect();
+        let exprs: String = self.exprs.iter()
+            .map(|(k, e)| format!("{}: {},", k.key(), e))
+            .collect();
+        let ramps: String = self.ramps.iter()
+            .map(|r| format!("{}: ramp to {}{} over {:?},", r.knob.key(), r.to, r.knob.unit(), r.over))
+            .collect();
+        let target = if self.target.is_cluster_wide() {
+            String::new()
+        } else {
+            format!("{},", self.target)
+        };
+        write!(f, "State{{{}{}{}{}{}{}{}{}}}", target, cpu_freq, gpu_freq, fan_speed, others, exprs, ramps, lasting_time)
     }
 }
 
 impl Debug for State {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let cpu_freq = match self.cpu_freq {
-            Some(x) => {
-                format!("CPU_Freq: {}MHz,",x)
-            },
-            None => {
-                String::new()
-            }
+        Display::fmt(self, f)
+    }
+}
+impl StateManager<'_> {
+    pub fn new(cluster: &Cluster, state: State) -> StateManager<'_> {
+        StateManager { 
+            start_state: state.clone(),
+            current_state: state, 
+            cluster: Some(cluster),
+            timeline: Timeline::new(Clock::Real(Instant::now())),
+            frequency_table: HashMap::new(),
+            nodes: vec![],
+            device_state: HashMap::new(),
+            stale: HashSet::new(),
+            runner: None,
+            readback: ReadBack::default(),
+            refused: None,
+            retry: Retry::default(),
+            health: Mutex::new(Health::default()),
+            power: Mutex::new(None),
+            safe: false,
+            envelope: Envelope::default(),
+            deferred: vec![],
+            fan_curve: None,
+            fan_curve_on: false,
+            fan_curve_at: None,
+            fan_sensor: None,
+        }
+    }
+    /// a state manager without hardware, used for replaying logs
+    pub fn dry_run<'a>(state: State, clock: Clock) -> StateManager<'a> {
+        StateManager {
+            start_state: state.clone(),
+            current_state: state,
+            cluster: None,
+            timeline: Timeline::new(clock),
+            frequency_table: HashMap::new(),
+            nodes: vec![],
+            device_state: HashMap::new(),
+            stale: HashSet::new(),
+            runner: None,
+            readback: ReadBack::default(),
+            refused: None,
+            retry: Retry::default(),
+            health: Mutex::new(Health::default()),
+            power: Mutex::new(None),
+            safe: false,
+            envelope: Envelope::default(),
+            deferred: vec![],
+            fan_curve: None,
+            fan_curve_on: false,
+            fan_curve_at: None,
+            fan_sensor: None,
+        }
+    }
+    /// read `"frequency_table": {"GPU_Freq": [390, 405, ...]}` of the application file
+    pub fn load_frequency_table(&mut self, v: &Value) {
+        let obj = v.as_object().expect("the frequency table must be an object");
+        for (key, values) in obj {
+            let knob = Knob::from_key(key).expect("unknown knob in the frequency table");
+            let mut table: Vec<usize> = values.as_array()
+                .expect("the frequency table of a knob must be an array")
+                .iter()
+                .map(|x| x.as_u64().expect("need a number").to_usize().unwrap())
+                .collect();
+            table.sort_unstable();
+            self.frequency_table.insert(knob, table);
+        }
+    }
+    /// the servers a state targeting GPUs or fans without a node applies to
+    pub fn set_nodes(&mut self, nodes: Vec<String>) {
+        self.nodes = nodes;
+    }
+    pub fn current_state(&self) -> &State {
+        &self.current_state
+    }
+    /// the value of `knob` on the part of the cluster given by `target`
+    pub fn current(&self, knob: Knob, target: &Target) -> Option<usize> {
+        target.devices(knob, &self.nodes)
+            .first()
+            .and_then(|d| self.device_state.get(&(knob, d.clone())).copied())
+            .or_else(|| self.current_state.get(knob))
+    }
+    pub fn set_readback(&mut self, readback: ReadBack) {
+        self.readback = readback;
+    }
+    pub fn readback(&self) -> &ReadBack {
+        &self.readback
+    }
+    pub fn set_envelope(&mut self, envelope: Envelope) {
+        self.envelope = envelope;
+    }
+    pub fn envelope(&self) -> &Envelope {
+        &self.envelope
+    }
+    pub fn set_fan_curve(&mut self, curve: FanCurve) {
+        self.fan_sensor = match curve.input {
+            Input::Temperature(_) => Some(Sensor::start(&curve)),
+            Input::Power => None,
         };
-        let gpu_freq = match self.gpu_freq {
-            Some(x) => {
-                format!("GPU_Freq: {}MHz,", x)
-            },
-            None => {
-                String::new()
-            }
+        self.fan_curve = Some(curve);
+    }
+    /// how long from `now` until the curve is due, when the fans follow it
+    fn fan_curve_wait(&self, now: Duration) -> Option<Duration> {
+        let curve = self.fan_curve.as_ref().filter(|_| self.fan_curve_on && !self.safe)?;
+        Some(self.fan_curve_at.map_or(Duration::ZERO, |t| (t + curve.interval).saturating_sub(now)))
+    }
+    /// set the fans by the curve when they follow it and it is due, `power` reads the power of the cluster,
+    /// a change of speed waits for the next evaluation when the envelope holds the fans back
+    pub fn follow_fan_curve(&mut self, power: &dyn Fn() -> usize) {
+        if !self.fan_curve_on || self.safe {
+            return;
+        }
+        let curve = match &self.fan_curve {
+            Some(c) => c.clone(),
+            None => return,
         };
-        let fan_speed = match self.fan_speed {
-            Some(x) => {
-                format!("Fan_Speed: {}%,", x)
+        let now = self.timeline.clock.now();
+        if self.fan_curve_at.is_some_and(|t| now.saturating_sub(t) < curve.interval) {
+            return;
+        }
+        self.fan_curve_at = Some(now);
+        let read = match (&curve.input, &self.fan_sensor) {
+            (Input::Temperature(_), Some(sensor)) => match sensor.latest() {
+                Some(x) => x,
+                None => return info!("[fan curve]no temperature is read yet"),
             },
-            None => {
-                String::new()
+            _ => curve.read(power()),
+        };
+        let x = match read {
+            Ok(x) => x,
+            Err(e) => return warn!("[fan curve]{}", e),
+        };
+        if let Some(speed) = curve.next(x, self.current(Knob::FanSpeed, &Target::default())) {
+            if let Err(why) = self.envelope.admit(Knob::FanSpeed.device(), now) {
+                warn!("[envelope]the fan curve waits to set {}%: {}", speed, why);
+                self.timeline.record("envelope", format!("fan curve: {}", why));
+                return;
+            }
+            info!("[fan curve]the input is {}, the fans go to {}%", x, speed);
+            self.set(Knob::FanSpeed, &Target::default(), speed);
+            self.timeline.record("fan curve", format!("{}% at {}", speed, x));
+        }
+    }
+    pub fn set_retry(&mut self, retry: Retry) {
+        self.retry = retry;
+    }
+    /// the commands sent to each node, for the report
+    pub fn health(&self) -> Value {
+        self.health.lock().unwrap().to_value()
+    }
+    /// why the run must stop, see `"fail_run"` of the read-back
+    pub fn refused(&self) -> Option<&str> {
+        self.refused.as_deref()
+    }
+    /// read back the values just set on the whole cluster, retrying while the hardware catches up,
+    /// the ones which still disagree are recorded in the timeline
+    fn verify(&mut self, applied: &[(Knob, usize)]) {
+        for (knob, value) in applied {
+            if !self.readback.has(*knob) {
+                continue;
+            }
+            let mut attempt = 0;
+            let discrepancy = loop {
+                let read = self.readback.read(*knob).unwrap();
+                match read {
+                    Ok(values) if self.readback.agrees(*value, &values) => break None,
+                    _ if attempt < self.readback.retries => {
+                        attempt += 1;
+                        self.timeline.clock.sleep(self.readback.retry_interval);
+                    },
+                    Ok(values) => break Some(format!("{}: set {}{}, read {:?}", knob.key(), value, knob.unit(), values)),
+                    Err(e) => break Some(format!("{}: set {}{}, {}", knob.key(), value, knob.unit(), e)),
+                }
+            };
+            if let Some(d) = discrepancy {
+                warn!("[verify]{} after {} retries", d, attempt);
+                self.timeline.record("verify", d.clone());
+                if self.readback.fail_run && self.refused.is_none() {
+                    self.refused = Some(d);
+                }
             }
+        }
+    }
+    /// the values `knob` supports, if they are known
+    pub fn frequency_table(&self, knob: Knob) -> Option<&[usize]> {
+        self.frequency_table.get(&knob).map(|t| t.as_slice())
+    }
+    pub fn timeline(&self) -> &Timeline {
+        &self.timeline
+    }
+    /// the total power of the cluster in W, read at most every `POWER_MAX_AGE`,
+    /// in a dry run the last power the logger has seen
+    pub fn power(&self) -> usize {
+        let cluster = match self.cluster {
+            Some(c) => c,
+            None => return unsafe { crate::logger::POWER },
         };
-        let lasting_time = match self.lasting_time {
-            Some(x) => {
-                format!("Lasting_time: {:?},", x)
-            },
-            None => {
-                String::new()
+        let mut last = self.power.lock().unwrap();
+        match *last {
+            Some((at, x)) if at.elapsed() < POWER_MAX_AGE => x,
+            _ => {
+                let x = cluster.collect_power_data(0).total_power;
+                *last = Some((Instant::now(), x));
+                x
+            }
+        }
+    }
+    /// the power of the node at `index` in the cluster file in W, 0 in a dry run
+    pub fn node_power(&self, index: usize) -> usize {
+        match self.cluster {
+            Some(c) => c.collect_power_data(index).total_power,
+            None => 0,
+        }
+    }
+    pub fn timeline_mut(&mut self) -> &mut Timeline {
+        &mut self.timeline
+    }
+    /// hand the commands to `runner` instead of the power controller, e.g. to fake a failing node
+    pub fn set_runner(&mut self, runner: impl Fn(&str) -> Result<(), String> + Send + Sync + 'static) {
+        self.runner = Some(Box::new(runner));
+    }
+    /// send one command with the retry policy, whether it succeeds, nothing is sent to a node which is down
+    fn send(&self, s: &str) -> bool {
+        self.send_to(s, false)
+    }
+    /// `send` also to the nodes which are down, for the safe state and the reset
+    fn send_always(&self, s: &str) -> bool {
+        self.send_to(s, true)
+    }
+    fn send_to(&self, s: &str, down_too: bool) -> bool {
+        let node = health::node_of(s);
+        if !down_too && self.health.lock().unwrap().is_down(node) {
+            warn!("[state switch]{} is down, {} is not sent", node, s);
+            return false;
+        }
+        let (result, attempts) = if let Some(runner) = &self.runner {
+            self.retry.run(|| runner(s))
+        } else if let Some(cluster) = self.cluster {
+            match Command::parse(cluster, s) {
+                Ok(c) => self.retry.run(|| cluster.run_command(&c).map_err(|e| e.to_string())),
+                Err(msg) => {
+                    error!("[state switch]the power controller can not parse {}: {}", s, msg);
+                    return false;
+                }
             }
+        } else {
+            info!("[dry run]{}", s);
+            return true;
         };
-        write!(f, "State{{{}{}{}{}}}", cpu_freq, gpu_freq, fan_speed, lasting_time)
+        match &result {
+            Ok(()) if attempts > 1 => info!("[state switch]{} succeeds after {} attempts", s, attempts),
+            Ok(()) => {},
+            Err(e) => warn!("[state switch]{} fails after {} attempts: {}", s, attempts, e),
+        }
+        self.health.lock().unwrap().record(node, attempts, &result, self.retry.max_failures);
+        // a command to the whole cluster which fails is sent node by node, so the failing nodes are found
+        if result.is_err() && node == health::CLUSTER && !self.nodes.is_empty() {
+            let commands: Vec<String> = self.nodes.iter().filter_map(|n| health::on_node(s, n)).collect();
+            if !commands.is_empty() {
+                let sent: Vec<bool> = commands.iter().map(|c| self.send_to(c, down_too)).collect();
+                return sent.iter().all(|x| *x);
+            }
+        }
+        result.is_ok()
     }
-}
-impl StateManager<'_> {
-    pub fn new(cluster: &Cluster, state: State) -> StateManager {
-        StateManager { 
-            current_state: state, 
-            cluster,
+    /// the values of the commands which failed may not be on the hardware, the next switch sends them again
+    fn forget_failed(&mut self, planned: &[Planned], sent: &[bool]) {
+        for ((key, command), ok) in planned.iter().zip(sent) {
+            if !ok {
+                warn!("[state switch]{} failed, it is sent again by the next switch", command);
+                self.stale.insert(key.clone());
+            }
         }
     }
-    pub fn set_cpu_freq(&self, target_freq: usize) {
-        info!("[state switch]change cpu frequency to {}MHz",target_freq);
-        let s = format!("SETFREQ CPU {freq}", freq = target_freq);
-        let command = Command::parse(self.cluster, &s);
-        match command {
-            Ok(c) => {
-                self.cluster.run_command(&c);
+    /// apply the failure policy to the nodes which went down since the last call
+    fn handle_failures(&mut self) {
+        let down = self.health.get_mut().unwrap().take_down();
+        if down.is_empty() {
+            return;
+        }
+        let nodes = down.join(", ");
+        error!("[state switch]{} failed {} commands in a row", nodes, self.retry.max_failures);
+        self.timeline.record("failure", format!("{} down", nodes));
+        match self.retry.on_failure.clone() {
+            OnFailure::Continue => warn!("[state switch]going on without {}", nodes),
+            OnFailure::Abort => {
+                if self.refused.is_none() {
+                    self.refused = Some(format!("the commands to {}", nodes));
+                }
             },
-            Err(msg) => {
-                println!("{}", msg);
-
+            OnFailure::SafeState(s) if !self.safe => {
+                warn!("[state switch]the cluster falls back to {}", s);
+                self.safe = true;
+                for knob in Knob::ALL {
+                    if let Some(x) = s.get(knob) {
+                        self.apply(knob, &Target::default(), x, true);
+                    }
+                }
+            },
+            OnFailure::SafeState(_) => {},
+        }
+    }
+    /// parse a raw command without running it
+    pub fn check_command(&self, s: &str) -> Result<(), String> {
+        match self.cluster {
+            Some(c) => Command::parse(c, s).map(|_| ()).map_err(|e| e.to_string()),
+            None => Ok(())
+        }
+    }
+    /// make sure the power controller has a command for every knob of `state`
+    pub fn check_state(&self, state: &State) -> Result<(), String> {
+        for knob in state.knobs() {
+            self.check_command(&knob.command(knob.device(), 0))
+                .map_err(|e| format!("the power controller can not set {}: {}", knob.key(), e))?;
+        }
+        Ok(())
+    }
+    /// run a raw command for the power controller
+    pub fn run_command(&mut self, s: &str) {
+        info!("[state switch]run command {}", s);
+        self.timeline.record("command", s.to_string());
+        self.send(s);
+    }
+    /// run a local shell command, it is killed if it lasts longer than `timeout`
+    pub fn run_shell(&mut self, command: &str, timeout: Duration) {
+        if self.cluster.is_none() {
+            info!("[dry run]shell: {}", command);
+            self.timeline.record("shell", format!("{} (dry run)", command));
+            return;
+        }
+        info!("[shell]{}", command);
+        let mut child = match shell::spawn(command) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("[shell]can not run {}: {}", command, e);
+                self.timeline.record("shell", format!("{} -> {}", command, e));
+                return;
             }
         };
+        let start = Instant::now();
+        let result = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break format!("{}", status),
+                Ok(None) if start.elapsed() >= timeout => {
+                    warn!("[shell]{} is killed after {:?}", command, timeout);
+                    shell::kill(&mut child);
+                    break format!("killed after {:?}", timeout);
+                },
+                Ok(None) => thread::sleep(SHELL_POLL_INTERVAL),
+                Err(e) => break format!("{}", e)
+            }
+        };
+        info!("[shell]{} -> {}", command, result);
+        self.timeline.record("shell", format!("{} -> {}", command, result));
+    }
+    /// the `set_*` of the whole cluster serve the reset and the cooling, they reach the nodes which are down too
+    pub fn set_cpu_freq(&self, target_freq: usize) {
+        info!("[state switch]change cpu frequency to {}MHz",target_freq);
+        self.send_always(&format!("SETFREQ CPU {freq}", freq = target_freq));
     } 
     pub fn set_gpu_freq(&self, target_freq: usize) {
         info!("[state switch]change gpu frequency to {}MHz",target_freq);
-        let s = format!("SETFREQ GPU {freq}", freq = target_freq);
-        let command = Command::parse(self.cluster, &s);
-        match command {
-            Ok(c) => {
-                self.cluster.run_command(&c);
-            },
-            Err(msg) => {
-                println!("{}", msg);
-
-            }
-        };
-
+        self.send_always(&format!("SETFREQ GPU {freq}", freq = target_freq));
     }
     pub fn set_fan_speed(&self, target_speed: usize) {
         info!("[state switch]change fan speed to {}%",target_speed);
-        let s = format!("SETSPEED FAN {speed}", speed = target_speed);
-        let command = Command::parse(self.cluster, &s);
-        match command {
-            Ok(c) => {
-                self.cluster.run_command(&c);
-            },
-            Err(msg) => {
-                println!("{}", msg);
-
+        self.send_always(&format!("SETSPEED FAN {speed}", speed = target_speed));
+    }
+    /// set one knob on the part of the cluster given by `target` and remember it
+    /// the result is whether every command was sent
+    pub fn set(&mut self, knob: Knob, target: &Target, value: usize) -> bool {
+        self.apply(knob, target, value, false)
+    }
+    /// `set`, with `down_too` also on the nodes which are down
+    fn apply(&mut self, knob: Knob, target: &Target, value: usize, down_too: bool) -> bool {
+        let value = self.within_envelope(knob, value);
+        let planned = self.plan(knob, target, value);
+        let sent: Vec<bool> = planned.iter().map(|(_, c)| self.send_to(c, down_too)).collect();
+        self.forget_failed(&planned, &sent);
+        self.handle_failures();
+        sent.iter().all(|x| *x)
+    }
+    /// `value` clamped into the envelope, a clamped value is logged
+    fn within_envelope(&mut self, knob: Knob, value: usize) -> usize {
+        let x = self.envelope.clamp(knob, value);
+        if x != value {
+            warn!("[envelope]{} {}{} is out of the envelope, {}{} is set instead", knob.key(), value, knob.unit(), x, knob.unit());
+            self.timeline.record("envelope", format!("{}: {}{} clamped to {}{}", knob.key(), value, knob.unit(), x, knob.unit()));
+            self.envelope.clamped += 1;
+        }
+        x
+    }
+    /// whether `knob` already has `value` everywhere on `target`
+    fn is_current(&self, knob: Knob, target: &Target, value: usize) -> bool {
+        let devices = target.devices(knob, &self.nodes);
+        if self.stale.contains(&(knob, None)) {
+            return false;
+        }
+        if devices.is_empty() {
+            return self.current_state.get(knob) == Some(value) && !self.device_state.keys().any(|(k, _)| *k == knob);
+        }
+        devices.into_iter().all(|d| !self.stale.contains(&(knob, Some(d.clone())))
+            && self.device_state.get(&(knob, d)).copied().or(self.current_state.get(knob)) == Some(value))
+    }
+    /// the commands setting `knob` on `target`, the new value is remembered as if they were sent,
+    /// `forget_failed` marks the ones which fail
+    fn plan(&mut self, knob: Knob, target: &Target, value: usize) -> Vec<Planned> {
+        let devices = target.devices(knob, &self.nodes);
+        if devices.is_empty() && target.narrows(knob) {
+            // never widen a part of the cluster to the whole of it
+            warn!("[state switch]no server is known for {}, {} is not set", target, knob.key());
+            self.timeline.record("skipped", format!("{}: no server for {}", knob.key(), target));
+            return vec![];
+        }
+        if devices.is_empty() {
+            info!("[state switch]change {} to {}{}", knob.key(), value, knob.unit());
+            self.current_state.set(knob, Some(value));
+            // the whole cluster has the same value again
+            self.device_state.retain(|(k, _), _| *k != knob);
+            self.stale.retain(|(k, _)| *k != knob);
+            return vec![((knob, None), knob.command(knob.device(), value))];
+        }
+        if let Some(node) = &target.node {
+            if !self.nodes.is_empty() && !self.nodes.contains(node) {
+                warn!("[state switch]{} is not a server of the cluster", node);
+            }
+        }
+        let mut commands = vec![];
+        for d in devices {
+            info!("[state switch]change {} of {} to {}{}", knob.key(), d, value, knob.unit());
+            commands.push(((knob, Some(d.clone())), knob.command(&d, value)));
+            self.stale.remove(&(knob, Some(d.clone())));
+            self.device_state.insert((knob, d), value);
+        }
+        commands
+    }
+    /// send the commands of one state switch at the same time, each one is a round-trip to a node,
+    /// whether each of them succeeds
+    fn send_all(&self, commands: &[String]) -> Vec<bool> {
+        if commands.len() <= 1 {
+            return commands.iter().map(|c| self.send(c)).collect();
+        }
+        thread::scope(|s| {
+            let handles: Vec<_> = commands.iter().map(|c| s.spawn(move || self.send(c))).collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        })
+    }
+    /// go through the steps of every ramp of the state, steps of different knobs are interleaved by time
+    fn run_ramps(&mut self, ramps: &[Ramp], target: &Target) {
+        let mut steps = vec![];
+        for r in ramps {
+            match self.current(r.knob, target) {
+                Some(from) => {
+                    let table = self.frequency_table.get(&r.knob).map(|t| t.as_slice());
+                    steps.extend(r.steps(from, table).into_iter().map(|(at, v)| (at, r.knob, v)));
+                },
+                // nothing to start from
+                None => steps.push((Duration::ZERO, r.knob, r.to))
+            }
+        }
+        steps.sort_by_key(|x| x.0);
+        let start = self.timeline.clock.now();
+        for (at, knob, v) in steps {
+            if self.safe {
+                break;
             }
+            self.timeline.clock.advance_to(start + at);
+            self.set(knob, target, v);
+            self.timeline.record("ramp", format!("{}: {}{}", knob.key(), v, knob.unit()));
+        }
+    }
+    /// keep a resolved value in the range of the knob, the fans have 0..100 and the other knobs
+    /// the frequency table or the range of the envelope, `None` when neither is known
+    fn clamp(&self, knob: Knob, x: f64) -> Option<usize> {
+        let x = x.max(0.0).round() as usize;
+        let x = match (knob, self.frequency_table.get(&knob)) {
+            (Knob::FanSpeed, _) => x.min(100),
+            (_, Some(t)) => ramp::nearest(t, x).unwrap_or(x),
+            (_, None) if self.envelope.range(knob).is_some() => x,
+            (_, None) => return None,
         };
+        Some(self.envelope.clamp(knob, x))
     }
-    pub fn switch_state(&mut self, mut target_state: State) {
-
+    /// the value of a name in an expression of a state for `target`
+    fn lookup(&self, name: &str, knob: Knob, target: &Target, captures: &Captures) -> Option<f64> {
+        match name {
+            "current" => self.current(knob, target).map(|x| x as f64),
+            "power" => Some(self.power() as f64),
+            "progress" => Some(unsafe { crate::execute::PROGRESS }),
+            _ => match Knob::ALL.iter().find(|k| k.key().eq_ignore_ascii_case(name)) {
+                Some(k) => self.current(*k, target).map(|x| x as f64),
+                None => captures.get(name).copied()
+            }
+        }
+    }
+    /// the values of the expressions of a state, all taken from the state before the switch
+    fn resolve(&self, target_state: &State, captures: &Captures) -> Vec<(Knob, usize)> {
+        let mut values = vec![];
+        for (knob, e) in &target_state.exprs {
+            let vars = |name: &str| self.lookup(name, *knob, &target_state.target, captures);
+            match e.eval(&vars).map(|x| self.clamp(*knob, x)) {
+                Ok(Some(v)) => {
+                    info!("[state switch]{} of {} is {}{}", e, knob.key(), v, knob.unit());
+                    values.push((*knob, v));
+                },
+                Ok(None) => warn!("[state switch]{} of {} is skipped: it needs a frequency_table or an envelope range", e, knob.key()),
+                Err(msg) => warn!("[state switch]{} of {} is skipped: {}", e, knob.key(), msg)
+            }
+        }
+        values
+    }
+    pub fn switch_state(&mut self, target_state: State) {
+        self.switch_state_with(target_state, &Captures::new());
+    }
+    /// switch to `target_state`, `captures` are the values its expressions can use besides the current state
+    pub fn switch_state_with(&mut self, target_state: State, captures: &Captures) {
+        self.timeline.record("state", format!("{}", target_state));
+        let sets_fans = target_state.fan_speed.is_some()
+            || target_state.exprs.iter().any(|(k, _)| *k == Knob::FanSpeed)
+            || target_state.ramps.iter().any(|r| r.knob == Knob::FanSpeed);
+        if target_state.fan_curve {
+            if self.fan_curve.is_none() {
+                warn!("[fan curve]there is no fan_curve, the fans keep their speed");
+            }
+            self.fan_curve_on = true;
+            // the curve is evaluated at once when it takes over
+            self.fan_curve_at = None;
+        } else if sets_fans {
+            self.fan_curve_on = false;
+        }
+        if self.safe {
+            info!("[state switch]the cluster is held at the safe state");
+            self.timeline.clock.sleep(target_state.lasting_time.unwrap_or(DEFAULT_LASTING_TIME));
+            return;
+        }
 
-        match target_state.cpu_freq {
-            Some(x) => {
-                self.set_cpu_freq(x);
-                self.current_state.cpu_freq = target_state.cpu_freq.take();
-            },
-            None =>{}
-        };
+        let mut changes: Vec<(Knob, usize)> = Knob::ALL.iter()
+            .filter_map(|k| target_state.get(*k).map(|x| (*k, x)))
+            .collect();
+        changes.extend(self.resolve(&target_state, captures));
+        // a later value of a knob replaces the one still held back by the envelope
+        let replaced: Vec<Knob> = changes.iter().map(|(k, _)| *k).chain(target_state.ramps.iter().map(|r| r.knob)).collect();
+        let held = self.deferred.len();
+        self.deferred.retain(|(t, h)| !(*t == target_state.target && replaced.contains(&h.knob())));
+        self.envelope.dropped += held - self.deferred.len();
+        self.flush_deferred();
+        let mut applied = vec![];
+        let mut pending = vec![];
+        let mut skipped = 0;
+        for (knob, x) in changes {
+            let x = self.within_envelope(knob, x);
+            applied.push((knob, x));
+            if self.is_current(knob, &target_state.target, x) {
+                skipped += 1;
+                continue;
+            }
+            pending.push((knob, x));
+        }
+        let mut ramps = target_state.ramps.clone();
+        // a switch changes each of its components once, however many knobs of it are set
+        let mut components: Vec<&'static str> = pending.iter().map(|(k, _)| k.device())
+            .chain(ramps.iter().map(|r| r.knob.device()))
+            .collect();
+        components.sort();
+        components.dedup();
+        let now = self.timeline.clock.now();
+        for c in components {
+            if let Err(why) = self.envelope.admit(c, now) {
+                warn!("[envelope]the switch of {} is deferred: {}", c, why);
+                self.timeline.record("envelope", format!("deferred: {}", why));
+                self.envelope.deferred += 1;
+                for (k, x) in pending.iter().filter(|(k, _)| k.device() == c) {
+                    self.deferred.push((target_state.target.clone(), Held::Value(*k, *x)));
+                }
+                for r in ramps.iter().filter(|r| r.knob.device() == c) {
+                    self.deferred.push((target_state.target.clone(), Held::Ramp(r.clone())));
+                }
+                pending.retain(|(k, _)| k.device() != c);
+                applied.retain(|(k, _)| k.device() != c);
+                ramps.retain(|r| r.knob.device() != c);
+            }
+        }
+        let mut planned = vec![];
+        for (knob, x) in pending {
+            planned.extend(self.plan(knob, &target_state.target, x));
+        }
+        let commands: Vec<String> = planned.iter().map(|(_, c)| c.clone()).collect();
+        let start = Instant::now();
+        let sent = self.send_all(&commands);
+        self.forget_failed(&planned, &sent);
+        if !commands.is_empty() || skipped > 0 {
+            let latency = start.elapsed();
+            info!("[state switch]{} commands in {:?}, {} unchanged knobs skipped", commands.len(), latency, skipped);
+            self.timeline.record("sent", format!("{} commands in {:?}, {} skipped", commands.len(), latency, skipped));
+        }
+        self.handle_failures();
 
-        match target_state.gpu_freq {
-            Some(x) => {
-                self.set_gpu_freq(x);
-                self.current_state.gpu_freq = target_state.gpu_freq.take();
-            },
-            None => {}
-        };
+        self.run_ramps(&ramps, &target_state.target);
+        applied.extend(ramps.iter().map(|r| (r.knob, self.envelope.clamp(r.knob, r.to))));
 
-        match target_state.fan_speed {
-            Some(x) => {
-                self.set_fan_speed(x);
-                self.current_state.fan_speed = target_state.fan_speed.take();
-            },
-            None => {}
+        // the read-back commands see the whole cluster, values of a part of it can not be checked
+        if self.readback.verify && target_state.target.is_cluster_wide() {
+            self.verify(&applied);
         }
 
-        sleep(match target_state.lasting_time {
+        self.pass(match target_state.lasting_time {
             Some(x) => {
                 x
             },
@@ -262,20 +1036,113 @@ impl StateManager<'_> {
         });
 
     }
+    /// send the parts of switches held back by the envelope whose component may change now
+    fn flush_deferred(&mut self) {
+        if self.safe {
+            self.deferred.clear();
+            return;
+        }
+        let mut components: Vec<&'static str> = self.deferred.iter().map(|(_, h)| h.knob().device()).collect();
+        components.sort();
+        components.dedup();
+        let now = self.timeline.clock.now();
+        for c in components {
+            if self.envelope.admit(c, now).is_err() {
+                continue;
+            }
+            let (due, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut self.deferred)
+                .into_iter()
+                .partition(|(_, h)| h.knob().device() == c);
+            self.deferred = rest;
+            for (target, held) in due {
+                info!("[envelope]the deferred switch of {} is sent: {:?}", c, held);
+                match held {
+                    Held::Value(knob, x) => {
+                        self.timeline.record("envelope", format!("sent after the wait: {} {}{}", knob.key(), x, knob.unit()));
+                        self.set(knob, &target, x);
+                    },
+                    Held::Ramp(r) => {
+                        self.timeline.record("envelope", format!("ramp after the wait: {} to {}{}", r.knob.key(), r.to, r.knob.unit()));
+                        self.run_ramps(&[r], &target);
+                    },
+                }
+            }
+        }
+    }
+    /// let `d` go by on the clock, the deferred switches are sent as soon as they may
+    /// and the fan curve is evaluated when it is due
+    fn pass(&mut self, d: Duration) {
+        let end = self.timeline.clock.now() + d;
+        loop {
+            let now = self.timeline.clock.now();
+            if now >= end {
+                break;
+            }
+            let next = self.deferred.iter()
+                .map(|(_, h)| self.envelope.wait(h.knob().device(), now))
+                .chain(self.fan_curve_wait(now))
+                .min();
+            match next {
+                Some(w) => {
+                    self.timeline.clock.sleep(w.max(MIN_DEFER_STEP).min(end - now));
+                    self.tick();
+                },
+                None => self.timeline.clock.sleep(end - now),
+            }
+        }
+    }
+    /// what is due between two switches: the switches deferred by the envelope and the fan curve
+    pub fn tick(&mut self) {
+        self.flush_deferred();
+        let cluster = self.cluster;
+        self.follow_fan_curve(&move || cluster.map_or(unsafe { crate::logger::POWER }, |c| c.collect_power_data(0).total_power));
+    }
 
-    pub fn reset(&self) {
+    /// send the current state again, fans handed to the curve are left to it
+    pub fn reset(&mut self) {
         let cpu_freq = self.current_state.cpu_freq.unwrap();
         let gpu_freq = self.current_state.gpu_freq.unwrap();
-        let fan_speed = self.current_state.fan_speed.unwrap();
         self.set_cpu_freq(cpu_freq);
         self.set_gpu_freq(gpu_freq);
-        self.set_fan_speed(fan_speed);
+        if self.current_state.fan_curve {
+            // the curve sets the fans at its next evaluation
+            self.fan_curve_on = true;
+            self.fan_curve_at = None;
+        } else {
+            self.set_fan_speed(self.current_state.fan_speed.unwrap());
+        }
+        // the other knobs are only restored when the start state has them
+        for knob in &Knob::OPTIONAL {
+            if let Some(x) = self.current_state.get(*knob) {
+                self.send_always(&knob.command(knob.device(), x));
+            }
+        }
+        for ((knob, d), v) in &self.device_state {
+            self.send_always(&knob.command(d, *v));
+        }
+    }
+    /// go back to the start state, e.g. after a warm-up, the knobs it has no value for are reset
+    pub fn restore_start(&mut self) {
+        let start = self.start_state.clone();
+        for knob in Knob::ALL {
+            if start.get(knob).is_none() && self.current_state.get(knob).is_some() {
+                self.send_always(&knob.reset_command());
+            }
+        }
+        self.timeline.record("state", format!("{}", start));
+        self.device_state.clear();
+        self.stale.clear();
+        self.deferred.clear();
+        self.fan_curve_on = start.fan_curve;
+        self.current_state = start;
+        self.reset();
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::sync::Arc;
     // some tests, the interface may can't handle some crushed input, so please don't do that
     #[test]
     fn test_complete_state_from_value() {
@@ -294,7 +1161,8 @@ mod test {
             gpu_freq: Some(390),
             cpu_freq: Some(1000),
             fan_speed: Some(40),
-            lasting_time: Some(Duration::from_millis(0))
+            lasting_time: Some(Duration::from_millis(0)),
+            ..State::default()
         });
 
     }
@@ -311,11 +1179,325 @@ mod test {
             gpu_freq: Some(765),
             cpu_freq: None,
             fan_speed: None,
-            lasting_time: None
+            lasting_time: None,
+            ..State::default()
         });
 
     }
     #[test]
+    fn test_ramp_from_value() {
+        let testv  = r#"
+        {
+            "GPU_Freq": {"to": 825, "over_ms": 2000, "step": 15},
+            "Fan_Speed": 60
+        }
+        "#;
+        let v = serde_json::from_str(testv).unwrap();
+        let s = State::from(&v);
+        assert_eq!(s.gpu_freq, None);
+        assert_eq!(s.fan_speed, Some(60));
+        assert_eq!(s.ramps.len(), 1);
+        assert_eq!(s.ramps[0].knob, Knob::GpuFreq);
+        assert_eq!(format!("{}", s), "State{Fan_Speed: 60%,GPU_Freq: ramp to 825MHz over 2s,}");
+    }
+    #[test]
+    fn test_target_devices() {
+        let testv  = r#"
+        {
+            "Node": "node2",
+            "GPUs": [1],
+            "GPU_Freq": 825,
+            "CPU_Freq": 1100
+        }
+        "#;
+        let v = serde_json::from_str(testv).unwrap();
+        let s = State::from(&v);
+        let nodes = vec!["node1".to_string(), "node2".to_string()];
+        assert_eq!(s.target.devices(Knob::GpuFreq, &nodes), vec!["GPU@node2.1"]);
+        assert_eq!(s.target.devices(Knob::CpuFreq, &nodes), vec!["CPU@node2"]);
+        let fans = Target { fans: Some(vec![0, 1]), ..Target::default() };
+        assert_eq!(fans.devices(Knob::FanSpeed, &nodes), vec!["FAN@node1.0", "FAN@node1.1", "FAN@node2.0", "FAN@node2.1"]);
+        assert!(fans.devices(Knob::GpuFreq, &nodes).is_empty());
+        assert!(fans.narrows(Knob::FanSpeed) && !fans.narrows(Knob::GpuFreq));
+        assert_eq!(format!("{}", s), "State{Target: node2 GPU[1],CPU_Freq: 1100MHz,GPU_Freq: 825MHz,}");
+    }
+    #[test]
+    fn test_relative_values() {
+        let testv  = r#"
+        {
+            "GPU_Freq": "+75",
+            "Fan_Speed": "current * 2",
+            "CPU_Freq": "nb * 5",
+            "Time": 0
+        }
+        "#;
+        let v = serde_json::from_str(testv).unwrap();
+        let s = State::from(&v);
+        assert_eq!(s.exprs.len(), 3);
+        assert_eq!(format!("{}", s), "State{CPU_Freq: nb * 5,GPU_Freq: +75,Fan_Speed: current * 2,Lasting_time: 0ns,}");
+        let mut sm = StateManager::dry_run(State::new(Some(1500), Some(600), Some(70), None), Clock::Virtual(Duration::ZERO));
+        sm.frequency_table.insert(Knob::GpuFreq, vec![600, 650, 690]);
+        let v = serde_json::from_str(r#"{"ranges": {"CPU_Freq": [800, 3000]}}"#).unwrap();
+        sm.set_envelope(Envelope::from(&v));
+        let captures = Captures::from([("nb".to_string(), 192.0)]);
+        sm.switch_state_with(s, &captures);
+        assert_eq!(sm.current(Knob::GpuFreq, &Target::default()), Some(690));
+        assert_eq!(sm.current(Knob::FanSpeed, &Target::default()), Some(100));
+        assert_eq!(sm.current(Knob::CpuFreq, &Target::default()), Some(960));
+    }
+    #[test]
+    fn test_relative_values_in_range() {
+        let mut sm = StateManager::dry_run(State::new(Some(1500), Some(600), Some(70), None), Clock::Virtual(Duration::ZERO));
+        let v = serde_json::from_str(r#"{"CPU_Freq": "-2000", "GPU_Freq": "-100", "Time": 0}"#).unwrap();
+        // no range is known for the GPUs, their value is not sent at all
+        sm.set_envelope(Envelope::from(&serde_json::from_str(r#"{"ranges": {"CPU_Freq": [800, 3000]}}"#).unwrap()));
+        sm.switch_state(State::from(&v));
+        assert_eq!(sm.current(Knob::CpuFreq, &Target::default()), Some(800));
+        assert_eq!(sm.current(Knob::GpuFreq, &Target::default()), Some(600));
+    }
+    #[test]
+    fn test_verify() {
+        let mut sm = StateManager::dry_run(State::new(Some(1500), Some(600), Some(70), None), Clock::Virtual(Duration::ZERO));
+        let v = serde_json::from_str(r#"
+        {
+            "commands": {"GPU_Freq": "echo 600; echo 600", "Fan_Speed": "echo 80"},
+            "verify": true, "retries": 1, "retry_ms": 10, "fail_run": true
+        }
+        "#).unwrap();
+        sm.set_readback(ReadBack::from(&v));
+        sm.switch_state(State::new(None, Some(825), Some(80), Some(Duration::ZERO)));
+        let verified: Vec<&String> = sm.timeline().entries().iter().filter(|e| e.kind == "verify").map(|e| &e.detail).collect();
+        assert_eq!(verified, vec!["GPU_Freq: set 825MHz, read [600.0, 600.0]"]);
+        assert_eq!(sm.refused(), Some("GPU_Freq: set 825MHz, read [600.0, 600.0]"));
+        // one retry waited on the virtual clock
+        assert_eq!(sm.timeline().clock.now(), Duration::from_millis(10));
+    }
+    #[test]
+    fn test_skip_unchanged() {
+        let mut sm = StateManager::dry_run(State::new(Some(1500), Some(600), Some(70), None), Clock::Virtual(Duration::ZERO));
+        sm.switch_state(State::new(Some(1500), Some(825), Some(70), Some(Duration::ZERO)));
+        sm.switch_state(State::new(Some(1500), Some(825), None, Some(Duration::ZERO)));
+        let sent: Vec<&String> = sm.timeline().entries().iter().filter(|e| e.kind == "sent").map(|e| &e.detail).collect();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[0].starts_with("1 commands in ") && sent[0].ends_with(", 2 skipped"));
+        assert!(sent[1].starts_with("0 commands in ") && sent[1].ends_with(", 2 skipped"));
+        assert_eq!(sm.current(Knob::GpuFreq, &Target::default()), Some(825));
+    }
+    #[test]
+    fn test_failure_policy() {
+        let mut sm = StateManager::dry_run(State::new(Some(1500), Some(600), Some(70), None), Clock::Virtual(Duration::ZERO));
+        let v = serde_json::from_str(r#"{"attempts": 3, "backoff_ms": 0, "max_failures": 1, "on_failure": "safe_state", "safe_state": {"GPU_Freq": 390, "Fan_Speed": 100}}"#).unwrap();
+        sm.set_retry(Retry::from(&v));
+        sm.set_nodes(vec!["node1".to_string(), "node2".to_string()]);
+        // node1 and node2 do not answer, the commands to the whole cluster go through
+        let tries = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&tries);
+        sm.set_runner(move |c| {
+            if c.contains("@node") {
+                *counter.lock().unwrap() += 1;
+                return Err("timeout".to_string());
+            }
+            Ok(())
+        });
+        let v = serde_json::from_str(r#"{"Node": "node1", "GPU_Freq": 825, "Time": 0}"#).unwrap();
+        sm.switch_state(State::from(&v));
+        assert_eq!(*tries.lock().unwrap(), 3);
+        assert_eq!(sm.current(Knob::GpuFreq, &Target::default()), Some(390));
+        assert_eq!(sm.current(Knob::FanSpeed, &Target::default()), Some(100));
+        // the strategy goes on but the cluster stays safe
+        sm.switch_state(State::new(None, Some(825), Some(40), Some(Duration::ZERO)));
+        assert_eq!(sm.current(Knob::GpuFreq, &Target::default()), Some(390));
+        assert_eq!(sm.health()["node1"]["down"], true);
+        let failures = sm.timeline().entries().iter().filter(|e| e.kind == "failure").count();
+        assert_eq!(failures, 1);
+        assert_eq!(sm.health()["node1"]["failed"], 1);
+        let v = serde_json::from_str(r#"{"attempts": 1, "max_failures": 1, "on_failure": "abort"}"#).unwrap();
+        sm.set_retry(Retry::from(&v));
+        let node2 = Target { node: Some("node2".to_string()), ..Target::default() };
+        sm.set(Knob::FanSpeed, &node2, 100);
+        assert_eq!(*tries.lock().unwrap(), 4);
+        assert_eq!(sm.refused(), Some("the commands to node2"));
+    }
+    #[test]
+    fn test_cluster_never_down() {
+        let mut sm = StateManager::dry_run(State::new(Some(1500), Some(600), Some(70), None), Clock::Virtual(Duration::ZERO));
+        let v = serde_json::from_str(r#"{"attempts": 1, "backoff_ms": 0, "max_failures": 1, "on_failure": "safe_state", "safe_state": {"Fan_Speed": 100}}"#).unwrap();
+        sm.set_retry(Retry::from(&v));
+        sm.set_nodes(vec!["node1".to_string(), "node2".to_string()]);
+        // every command fails, the ones to the whole cluster and the ones to a node
+        let sent = Arc::new(Mutex::new(vec![]));
+        let log = Arc::clone(&sent);
+        sm.set_runner(move |c| {
+            log.lock().unwrap().push(c.to_string());
+            Err("timeout".to_string())
+        });
+        sm.switch_state(State::new(None, Some(825), None, Some(Duration::ZERO)));
+        let sent = sent.lock().unwrap().clone();
+        assert_eq!(&sent[..3], ["SETFREQ GPU 825", "SETFREQ GPU@node1 825", "SETFREQ GPU@node2 825"]);
+        // both nodes are down, the safe state is still tried on the whole cluster and on each of them
+        assert_eq!(&sent[3..], ["SETSPEED FAN 100", "SETSPEED FAN@node1 100", "SETSPEED FAN@node2 100"]);
+        let health = sm.health();
+        assert_eq!((health["node1"]["down"].as_bool(), health["node2"]["down"].as_bool()), (Some(true), Some(true)));
+        assert_eq!(health["cluster"]["down"], false);
+    }
+    #[test]
+    fn test_resend_failed() {
+        let mut sm = StateManager::dry_run(State::new(Some(1500), Some(600), Some(70), None), Clock::Virtual(Duration::ZERO));
+        let v = serde_json::from_str(r#"{"attempts": 1, "backoff_ms": 0, "max_failures": 10}"#).unwrap();
+        sm.set_retry(Retry::from(&v));
+        sm.set_nodes(vec!["node1".to_string()]);
+        // every command fails the first time it is sent
+        let sent = Arc::new(Mutex::new(vec![]));
+        let log = Arc::clone(&sent);
+        sm.set_runner(move |c| {
+            let mut log = log.lock().unwrap();
+            let first = !log.iter().any(|x: &String| x == c);
+            log.push(c.to_string());
+            if first { Err("timeout".to_string()) } else { Ok(()) }
+        });
+        let count = |c: &str| sent.lock().unwrap().iter().filter(|x| *x == c).count();
+        let gpu = State::new(None, Some(825), None, Some(Duration::ZERO));
+        sm.switch_state(gpu.clone());
+        sm.switch_state(gpu.clone());
+        sm.switch_state(gpu);
+        assert_eq!(count("SETFREQ GPU 825"), 2);
+        let v = serde_json::from_str(r#"{"Node": "node1", "GPUs": [0], "GPU_Freq": 390, "Time": 0}"#).unwrap();
+        let node = State::from(&v);
+        sm.switch_state(node.clone());
+        sm.switch_state(node.clone());
+        sm.switch_state(node);
+        assert_eq!(count("SETFREQ GPU@node1.0 390"), 2);
+    }
+    #[test]
+    fn test_envelope() {
+        let mut sm = StateManager::dry_run(State::new(Some(1500), Some(600), Some(70), None), Clock::Virtual(Duration::ZERO));
+        let v = serde_json::from_str(r#"{"min_fan_speed": 30, "min_dwell_ms": 500}"#).unwrap();
+        sm.set_envelope(Envelope::from(&v));
+        sm.switch_state(State::new(None, Some(825), Some(0), Some(Duration::from_millis(100))));
+        assert_eq!(sm.current(Knob::FanSpeed, &Target::default()), Some(30));
+        // too soon for the GPUs, the CPUs have not changed yet
+        sm.switch_state(State::new(Some(1800), Some(390), None, Some(Duration::ZERO)));
+        assert_eq!(sm.current(Knob::GpuFreq, &Target::default()), Some(825));
+        assert_eq!(sm.current(Knob::CpuFreq, &Target::default()), Some(1800));
+        assert_eq!((sm.envelope().clamped, sm.envelope().deferred), (1, 1));
+        // the GPUs get their value once the dwell is over
+        sm.switch_state(State::new(None, None, None, Some(Duration::from_millis(300))));
+        assert_eq!(sm.current(Knob::GpuFreq, &Target::default()), Some(825));
+        sm.switch_state(State::new(None, None, None, Some(Duration::from_millis(200))));
+        assert_eq!(sm.current(Knob::GpuFreq, &Target::default()), Some(390));
+        assert_eq!(sm.timeline().clock.now(), Duration::from_millis(600));
+        // a deferred value replaced by a later switch is never sent
+        sm.switch_state(State::new(None, Some(600), None, Some(Duration::ZERO)));
+        sm.switch_state(State::new(None, Some(825), None, Some(Duration::ZERO)));
+        sm.tick();
+        assert_eq!(sm.current(Knob::GpuFreq, &Target::default()), Some(390));
+        sm.switch_state(State::new(None, None, None, Some(Duration::from_millis(500))));
+        assert_eq!(sm.current(Knob::GpuFreq, &Target::default()), Some(825));
+        assert_eq!((sm.envelope().deferred, sm.envelope().dropped), (3, 1));
+        // a direct setting, such as a ramp step, is only clamped
+        sm.set(Knob::FanSpeed, &Target::default(), 10);
+        assert_eq!(sm.current(Knob::FanSpeed, &Target::default()), Some(30));
+    }
+    #[test]
+    fn test_fan_curve() {
+        let mut sm = StateManager::dry_run(State::new(Some(1500), Some(600), Some(40), None), Clock::Virtual(Duration::ZERO));
+        let v = serde_json::from_str(r#"{"points": [[800, 30], [1200, 60], [1500, 100]], "hysteresis": 100}"#).unwrap();
+        sm.set_fan_curve(FanCurve::from(&v));
+        let fans = |sm: &StateManager| sm.current(Knob::FanSpeed, &Target::default());
+        sm.follow_fan_curve(&|| 1350);
+        assert_eq!(fans(&sm), Some(40));
+        let v = serde_json::from_str(r#"{"Fan_Speed": "curve", "Time": 0}"#).unwrap();
+        sm.switch_state(State::from(&v));
+        sm.follow_fan_curve(&|| 1350);
+        assert_eq!(fans(&sm), Some(80));
+        // not due yet
+        sm.follow_fan_curve(&|| 2000);
+        assert_eq!(fans(&sm), Some(80));
+        sm.timeline_mut().clock.sleep(Duration::from_secs(1));
+        sm.follow_fan_curve(&|| 1300);
+        assert_eq!(fans(&sm), Some(80));
+        sm.switch_state(State::new(None, None, Some(50), Some(Duration::from_secs(1))));
+        sm.follow_fan_curve(&|| 2000);
+        assert_eq!(fans(&sm), Some(50));
+    }
+    #[test]
+    fn test_fan_curve_start() {
+        let v = serde_json::from_str(r#"{"CPU_Freq": 1500, "GPU_Freq": 600, "Fan_Speed": "curve"}"#).unwrap();
+        let mut sm = StateManager::dry_run(State::from(&v), Clock::Virtual(Duration::ZERO));
+        let v = serde_json::from_str(r#"{"points": [[800, 30], [1200, 60], [1500, 100]]}"#).unwrap();
+        sm.set_fan_curve(FanCurve::from(&v));
+        let fans = |sm: &StateManager| sm.current(Knob::FanSpeed, &Target::default());
+        // the fans of the start state are left to the curve
+        sm.reset();
+        sm.follow_fan_curve(&|| 1350);
+        assert_eq!(fans(&sm), Some(80));
+        sm.switch_state(State::new(None, None, Some(50), Some(Duration::ZERO)));
+        sm.follow_fan_curve(&|| 1500);
+        assert_eq!(fans(&sm), Some(50));
+        sm.restore_start();
+        sm.follow_fan_curve(&|| 1500);
+        assert_eq!(fans(&sm), Some(100));
+    }
+    #[test]
+    fn test_fan_curve_while_lasting() {
+        let mut sm = StateManager::dry_run(State::new(Some(1500), Some(600), Some(40), None), Clock::Virtual(Duration::ZERO));
+        let v = serde_json::from_str(r#"{"min_dwell_ms": 500}"#).unwrap();
+        sm.set_envelope(Envelope::from(&v));
+        let v = serde_json::from_str(r#"
+        {"input": "temperature", "temperature_command": "echo 70", "points": [[60, 40], [80, 80]], "interval_ms": 100}
+        "#).unwrap();
+        sm.set_fan_curve(FanCurve::from(&v));
+        let start = Instant::now();
+        while sm.fan_sensor.as_ref().unwrap().latest().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        sm.switch_state(State::new(None, None, Some(50), Some(Duration::ZERO)));
+        // the curve is evaluated while the state lasts, the fans wait for the dwell
+        let v = serde_json::from_str(r#"{"Fan_Speed": "curve", "Time": 1000}"#).unwrap();
+        sm.switch_state(State::from(&v));
+        assert_eq!(sm.current(Knob::FanSpeed, &Target::default()), Some(60));
+        let changes: Vec<Duration> = sm.timeline().entries().iter().filter(|e| e.kind == "fan curve").map(|e| e.at).collect();
+        assert_eq!(changes, vec![Duration::from_millis(501)]);
+        assert_eq!(sm.timeline().clock.now(), Duration::from_secs(1));
+    }
+    #[test]
+    fn test_target_without_nodes() {
+        let mut sm = StateManager::dry_run(State::new(Some(1500), Some(600), Some(70), None), Clock::Virtual(Duration::ZERO));
+        let v = serde_json::from_str(r#"{"GPUs": [0], "GPU_Freq": 825, "Time": 0}"#).unwrap();
+        sm.switch_state(State::from(&v));
+        assert_eq!(sm.current_state().gpu_freq, Some(600));
+        assert!(sm.device_state.is_empty());
+        sm.set(Knob::FanSpeed, &Target { fans: Some(vec![1]), ..Target::default() }, 100);
+        assert_eq!(sm.current_state().fan_speed, Some(70));
+    }
+    #[test]
+    fn test_more_knobs() {
+        let v = serde_json::from_str(r#"
+        {"GPU_Power_Limit": 300, "GPU_Mem_Freq": 1215, "CPU_Power_Cap": 200, "CPU_Uncore_Freq": "+100"}
+        "#).unwrap();
+        let s = State::from(&v);
+        assert_eq!(s.get(Knob::GpuPowerLimit), Some(300));
+        assert_eq!(s.get(Knob::GpuMemFreq), Some(1215));
+        assert_eq!(s.get(Knob::CpuPowerCap), Some(200));
+        assert_eq!(s.knobs(), Knob::OPTIONAL.to_vec());
+        assert_eq!(Knob::GpuPowerLimit.command("GPU@node1.0", 300), "SETPOWER GPU@node1.0 300");
+        assert_eq!(Knob::CpuPowerCap.command("CPU", 200), "SETPOWER CPU 200");
+        assert_eq!(Knob::GpuMemFreq.command("GPU", 1215), "SETMEMFREQ GPU 1215");
+        assert_eq!(Knob::CpuUncoreFreq.command("CPU@node2", 2000), "SETUNCOREFREQ CPU@node2 2000");
+        assert_eq!(Knob::reset_commands(), vec![
+            "RESET FAN", "RESET GPU", "RESET CPU", "RESETPOWER GPU", "RESETMEMFREQ GPU", "RESETPOWER CPU", "RESETUNCOREFREQ CPU",
+        ]);
+        // a relative value needs a current one to start from
+        let mut start = State::new(Some(1500), Some(600), Some(70), None);
+        start.set(Knob::GpuPowerLimit, Some(250));
+        let mut sm = StateManager::dry_run(start, Clock::Virtual(Duration::ZERO));
+        sm.switch_state(State::from(&v));
+        assert_eq!(sm.current(Knob::CpuUncoreFreq, &Target::default()), None);
+        assert_eq!(sm.current(Knob::GpuPowerLimit, &Target::default()), Some(300));
+        assert!(sm.check_state(&s).is_ok());
+    }
+    #[test]
     fn test_display() {
         let testv  = r#"
         {
@@ -0,0 +1,216 @@
+use crate::{State, StateManager};
+use crate::expr::Captures;
+use regex::Regex;
+use serde_json::Value;
+use std::fmt::{self, Debug, Display};
+use std::time::Duration;
+
+const DEFAULT_SHELL_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Cmp {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl Cmp {
+    fn parse(s: &str) -> Option<Cmp> {
+        match s {
+            "<" => Some(Cmp::Less),
+            "<=" => Some(Cmp::LessEqual),
+            ">" => Some(Cmp::Greater),
+            ">=" => Some(Cmp::GreaterEqual),
+            _ => None,
+        }
+    }
+    fn holds(&self, a: f64, b: f64) -> bool {
+        match self {
+            Cmp::Less => a < b,
+            Cmp::LessEqual => a <= b,
+            Cmp::Greater => a > b,
+            Cmp::GreaterEqual => a >= b,
+        }
+    }
+    fn symbol(&self) -> &'static str {
+        match self {
+            Cmp::Less => "<",
+            Cmp::LessEqual => "<=",
+            Cmp::Greater => ">",
+            Cmp::GreaterEqual => ">=",
+        }
+    }
+}
+
+/// what a wait step waits for, written as `power < 1200`, `progress >= 90` or `output ~ regex`
+#[derive(Clone, Debug)]
+pub enum Condition {
+    /// the total power of the cluster in W
+    Power(Cmp, f64),
+    Progress(Cmp, f64),
+    /// a line of output matching the regex
+    Output(Regex),
+}
+
+impl Condition {
+    pub fn parse(s: &str) -> Result<Condition, String> {
+        let s = s.trim();
+        let (lhs, rest) = s.split_once(char::is_whitespace).ok_or(format!("can not understand the condition {}", s))?;
+        let (op, rhs) = rest.trim_start().split_once(char::is_whitespace).unwrap_or((rest.trim(), ""));
+        let rhs = rhs.trim();
+        if lhs == "output" {
+            if op != "~" {
+                return Err(format!("output can only be matched with ~ in {}", s));
+            }
+            return Regex::new(rhs).map(Condition::Output).map_err(|e| format!("{}", e));
+        }
+        let cmp = Cmp::parse(op).ok_or(format!("unknown comparison {} in {}", op, s))?;
+        let value: f64 = rhs.trim_end_matches(|c: char| c.is_alphabetic() || c == '%')
+            .trim()
+            .parse()
+            .map_err(|_| format!("need a number in {}", s))?;
+        match lhs {
+            "power" => Ok(Condition::Power(cmp, value)),
+            "progress" => Ok(Condition::Progress(cmp, value)),
+            _ => Err(format!("unknown quantity {} in {}, use power, progress or output", lhs, s)),
+        }
+    }
+    /// whether `holds` looks at the power, reading it is a round-trip to the cluster
+    pub fn needs_power(&self) -> bool {
+        matches!(self, Condition::Power(..))
+    }
+    /// `line` is the line of output just read, if any
+    pub fn holds(&self, power: usize, progress: f64, line: Option<&str>) -> bool {
+        match self {
+            Condition::Power(cmp, x) => cmp.holds(power as f64, *x),
+            Condition::Progress(cmp, x) => cmp.holds(progress, *x),
+            Condition::Output(re) => line.is_some_and(|l| re.is_match(l)),
+        }
+    }
+}
+
+impl PartialEq for Condition {
+    fn eq(&self, other: &Self) -> bool {
+        format!("{}", self) == format!("{}", other)
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::Power(cmp, x) => write!(f, "power {} {}W", cmp.symbol(), x),
+            Condition::Progress(cmp, x) => write!(f, "progress {} {}%", cmp.symbol(), x),
+            Condition::Output(re) => write!(f, "output ~ {}", re),
+        }
+    }
+}
+
+/// one step of an action, executed in order
+#[derive(Clone, PartialEq)]
+pub enum Step {
+    State(Box<State>),
+    /// a raw command for the power controller, such as `{"Command": "SETFREQ GPU 825"}`
+    Command(String),
+    /// a local shell command, killed after the timeout, such as `{"Shell": "nvidia-smi -q", "Timeout": 5000}`
+    Shell {
+        command: String,
+        timeout: Duration,
+    },
+    /// wait until the condition holds, at most the timeout,
+    /// such as `{"Wait": "power < 1200", "Timeout": 5000}`
+    Wait {
+        condition: Condition,
+        timeout: Duration,
+    },
+}
+
+impl From<&Value> for Step {
+    fn from(c: &Value) -> Self {
+        let obj = c.as_object().expect("the step in action must be an object");
+        if let Some(x) = obj.get("Command") {
+            return Step::Command(x.as_str().expect("Command must be a string").to_string());
+        }
+        if let Some(x) = obj.get("Shell") {
+            return Step::Shell {
+                command: x.as_str().expect("Shell must be a string").to_string(),
+                timeout: match obj.get("Timeout") {
+                    Some(t) => Duration::from_millis(t.as_u64().expect("need a number of milisecond")),
+                    None => DEFAULT_SHELL_TIMEOUT,
+                },
+            };
+        }
+        if let Some(x) = obj.get("Wait") {
+            let condition = x.as_str().expect("Wait must be a string");
+            return Step::Wait {
+                condition: Condition::parse(condition).unwrap_or_else(|e| panic!("{}", e)),
+                timeout: match obj.get("Timeout") {
+                    Some(t) => Duration::from_millis(t.as_u64().expect("need a number of milisecond")),
+                    None => DEFAULT_WAIT_TIMEOUT,
+                },
+            };
+        }
+        Step::State(Box::new(State::from(c)))
+    }
+}
+
+impl Step {
+    /// make sure a raw command can be parsed before the application starts
+    pub fn check(&self, state_manager: &StateManager) -> Result<(), String> {
+        match self {
+            Step::Command(c) => state_manager.check_command(c),
+            Step::State(s) => state_manager.check_state(s),
+            _ => Ok(()),
+        }
+    }
+    /// wait steps are handled by the executor, which keeps reading output meanwhile
+    pub fn execute(&self, state_manager: &mut StateManager, captures: &Captures) {
+        match self {
+            Step::State(s) => state_manager.switch_state_with(State::clone(s), captures),
+            Step::Command(c) => state_manager.run_command(c),
+            Step::Shell { command, timeout } => state_manager.run_shell(command, *timeout),
+            Step::Wait { .. } => {}
+        }
+    }
+}
+
+impl Debug for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::State(s) => Debug::fmt(s, f),
+            Step::Command(c) => write!(f, "Command{{{}}}", c),
+            Step::Shell { command, timeout } => write!(f, "Shell{{{}, timeout: {:?}}}", command, timeout),
+            Step::Wait { condition, timeout } => write!(f, "Wait{{{}, timeout: {:?}}}", condition, timeout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_step_from_value() {
+        let v = serde_json::from_str(r#"{"Command": "RESET GPU"}"#).unwrap();
+        assert_eq!(Step::from(&v), Step::Command("RESET GPU".to_string()));
+        let v = serde_json::from_str(r#"{"Shell": "sync", "Timeout": 500}"#).unwrap();
+        assert_eq!(Step::from(&v), Step::Shell { command: "sync".to_string(), timeout: Duration::from_millis(500) });
+        let v = serde_json::from_str(r#"{"GPU_Freq": 765}"#).unwrap();
+        assert_eq!(format!("{:?}", Step::from(&v)), "State{GPU_Freq: 765MHz,}");
+        let v = serde_json::from_str(r#"{"Wait": "power < 1200 W", "Timeout": 5000}"#).unwrap();
+        assert_eq!(format!("{:?}", Step::from(&v)), "Wait{power < 1200W, timeout: 5s}");
+    }
+    #[test]
+    fn test_condition() {
+        let c = Condition::parse("progress >= 90").unwrap();
+        assert!(c.holds(0, 90.0, None));
+        assert!(!c.holds(0, 89.5, None));
+        let c = Condition::parse("output ~ PCOL\\s+L").unwrap();
+        assert!(c.holds(0, 0.0, Some("PCOL  L")));
+        assert!(!c.holds(0, 0.0, None));
+        assert!(Condition::parse("temperature < 80").is_err());
+        assert!(Condition::parse("power = 80").is_err());
+        assert!(Condition::parse("power < 1200").unwrap().needs_power());
+        assert!(!Condition::parse("progress > 10").unwrap().needs_power());
+    }
+}
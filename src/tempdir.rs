@@ -0,0 +1,61 @@
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// how many random names are tried before giving up
+const ATTEMPTS: usize = 16;
+
+/// a directory in the temporary directory which only the launcher may enter, removed with its files when dropped
+/// the name is random, so another user can not create the paths in it first
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn create(prefix: &str) -> io::Result<TempDir> {
+        let mut last = None;
+        for _ in 0..ATTEMPTS {
+            // a hasher of a new `RandomState` starts from random keys
+            let mut h = RandomState::new().build_hasher();
+            h.write_u32(process::id());
+            let path = env::temp_dir().join(format!("{}.{:016x}", prefix, h.finish()));
+            match DirBuilder::new().mode(0o700).create(&path) {
+                Ok(()) => return Ok(TempDir { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => last = Some(e),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last.unwrap())
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    #[test]
+    fn test_temp_dir() {
+        let a = TempDir::create("app_launcher.test").unwrap();
+        let b = TempDir::create("app_launcher.test").unwrap();
+        assert_ne!(a.path(), b.path());
+        assert_eq!(fs::metadata(a.path()).unwrap().permissions().mode() & 0o777, 0o700);
+        fs::write(a.path().join("x"), "x").unwrap();
+        let path = a.path().to_path_buf();
+        drop(a);
+        assert!(!path.exists());
+    }
+}
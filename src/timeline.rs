@@ -0,0 +1,170 @@
+use serde_json::{json, Value};
+use std::{
+    fmt::{self, Display},
+    fs,
+    io,
+    path::Path,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// the file of the timeline in the run directory
+pub const TIMELINE_FILE: &str = "timeline.txt";
+
+/// the clock used by the state manager
+/// a real clock sleeps, a virtual one only moves forward (used when replaying logs)
+pub enum Clock {
+    Real(Instant),
+    Virtual(Duration),
+}
+
+impl Clock {
+    pub fn now(&self) -> Duration {
+        match self {
+            Clock::Real(start) => start.elapsed(),
+            Clock::Virtual(t) => *t,
+        }
+    }
+    /// the time of an instant taken elsewhere, as seen by this clock
+    pub fn at(&self, t: Instant) -> Duration {
+        match self {
+            Clock::Real(start) => t.saturating_duration_since(*start),
+            Clock::Virtual(now) => *now,
+        }
+    }
+    pub fn sleep(&mut self, d: Duration) {
+        match self {
+            Clock::Real(_) => sleep(d),
+            Clock::Virtual(t) => *t += d,
+        }
+    }
+    /// move the clock to `t`, never backwards
+    pub fn advance_to(&mut self, t: Duration) {
+        let now = self.now();
+        if t > now {
+            self.sleep(t - now);
+        }
+    }
+}
+
+pub struct Entry {
+    pub at: Duration,
+    pub progress: f64,
+    /// the last power read by the logger and the idle power, in W
+    pub power: usize,
+    pub baseline: usize,
+    pub kind: &'static str,
+    pub detail: String,
+}
+
+impl Entry {
+    /// the power above idle, only known with a baseline and a power read by the logger
+    pub fn net(&self) -> Option<usize> {
+        (self.baseline > 0 && self.power > 0).then(|| self.power.saturating_sub(self.baseline))
+    }
+    pub fn to_value(&self) -> Value {
+        json!({
+            "at_s": self.at.as_secs_f64(),
+            "progress": self.progress,
+            "power_w": self.power,
+            "baseline_w": (self.baseline > 0).then_some(self.baseline),
+            "net_w": self.net(),
+            "kind": self.kind,
+            "detail": self.detail,
+        })
+    }
+}
+
+/// what happened during a run, in order
+pub struct Timeline {
+    pub clock: Clock,
+    entries: Vec<Entry>,
+}
+
+impl Timeline {
+    pub fn new(clock: Clock) -> Timeline {
+        Timeline {
+            clock,
+            entries: vec![],
+        }
+    }
+    pub fn record(&mut self, kind: &'static str, detail: String) {
+        let (progress, power, baseline) = unsafe { (crate::execute::PROGRESS, crate::logger::POWER, crate::logger::BASELINE) };
+        self.entries.push(Entry {
+            at: self.clock.now(),
+            progress,
+            power,
+            baseline,
+            kind,
+            detail,
+        });
+    }
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+    pub fn to_value(&self) -> Value {
+        Value::Array(self.entries.iter().map(|e| e.to_value()).collect())
+    }
+    /// write the timeline as text in `dir`, one entry per line
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        fs::write(dir.join(TIMELINE_FILE), self.to_string())
+    }
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{:>10.3}s] {:>6.2}% ", self.at.as_secs_f64(), self.progress)?;
+        // the power is only known when the logger runs, and is only worth showing above the idle power
+        if let Some(net) = self.net() {
+            write!(f, "{:>5}W (+{}W) ", self.power, net)?;
+        }
+        write!(f, "{:<8} {}", self.kind, self.detail)
+    }
+}
+
+impl Display for Timeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for e in &self.entries {
+            writeln!(f, "{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_virtual_clock_never_goes_back() {
+        let mut c = Clock::Virtual(Duration::from_secs(0));
+        c.sleep(Duration::from_millis(5));
+        c.advance_to(Duration::from_millis(3));
+        assert_eq!(c.now(), Duration::from_millis(5));
+        c.advance_to(Duration::from_millis(8));
+        assert_eq!(c.now(), Duration::from_millis(8));
+    }
+    #[test]
+    fn test_save() {
+        let mut t = Timeline::new(Clock::Virtual(Duration::from_millis(1500)));
+        t.record("sent", "3 commands".to_string());
+        let v = t.to_value();
+        assert_eq!((v[0]["at_s"].as_f64(), v[0]["kind"].as_str()), (Some(1.5), Some("sent")));
+        let dir = std::env::temp_dir().join(format!("app_launcher.timeline.{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        t.save(&dir).unwrap();
+        let text = fs::read_to_string(dir.join(TIMELINE_FILE)).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(text, t.to_string());
+        assert!(text.contains("sent") && text.contains("3 commands"));
+    }
+    #[test]
+    fn test_net_power() {
+        let mut e = Entry { at: Duration::from_secs(2), progress: 40.0, power: 1300, baseline: 900, kind: "state", detail: String::new() };
+        let v = e.to_value();
+        assert_eq!((v["power_w"].as_u64(), v["baseline_w"].as_u64(), v["net_w"].as_u64()), (Some(1300), Some(900), Some(400)));
+        assert!(e.to_string().contains(" 1300W (+400W) "));
+        e.baseline = 0;
+        let v = e.to_value();
+        assert!(v["baseline_w"].is_null() && v["net_w"].is_null());
+    }
+}